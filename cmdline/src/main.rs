@@ -6,6 +6,9 @@
 // Plan B: EVE route planner with options
 // Command-line demo client
 
+use std::path::PathBuf;
+use std::time::{SystemTime, UNIX_EPOCH};
+
 use structopt::StructOpt;
 
 use plan_b::*;
@@ -14,72 +17,398 @@ use plan_b::*;
 
 #[derive(StructOpt, Debug)]
 #[structopt(name = "plan-b")]
+struct Args {
+    #[structopt(long = "map", parse(from_os_str))]
+    map: Option<PathBuf>,
+    #[structopt(long = "bridges", parse(from_os_str))]
+    bridges: Option<PathBuf>,
+    #[structopt(long = "alliance", number_of_values = 1, requires = "bridges")]
+    alliance: Vec<String>,
+    #[structopt(long = "wormholes", parse(from_os_str))]
+    wormholes: Option<PathBuf>,
+    #[structopt(long = "ship-size", default_value = "small")]
+    ship_size: WormholeSize,
+    #[structopt(short = "j", long = "threads", default_value = "0")]
+    threads: usize,
+    #[structopt(subcommand)]
+    opt: Opt,
+}
+
+#[derive(StructOpt, Debug)]
 enum Opt {
     Diameter,
+    CompileMap,
+    CompileApsp,
     Route {
-        #[structopt(short = "a", long = "all")]
+        #[structopt(
+            short = "a",
+            long = "all",
+            conflicts_with_all = &["prefer", "via", "sparse"]
+        )]
         all: bool,
+        #[structopt(short = "c", long = "count", requires = "all")]
+        count: bool,
+        #[structopt(short = "l", long = "limit", requires = "all")]
+        limit: Option<usize>,
+        #[structopt(short = "k", long = "k", conflicts_with_all = &["all", "via", "sparse"])]
+        k: Option<usize>,
+        #[structopt(short = "s", long = "sparse")]
+        sparse: bool,
+        #[structopt(short = "p", long = "prefer", default_value = "shortest")]
+        prefer: Preference,
+        #[structopt(long = "penalty", default_value = "50")]
+        penalty: usize,
+        #[structopt(long = "avoid", number_of_values = 1)]
+        avoid: Vec<String>,
+        #[structopt(long = "via", number_of_values = 1)]
+        via: Vec<String>,
+        #[structopt(name = "START")]
+        start: String,
+        #[structopt(name = "GOAL")]
+        goal: String,
+    },
+    Jump {
+        #[structopt(short = "r", long = "range")]
+        range: f64,
+        #[structopt(short = "s", long = "ship")]
+        ship: Option<JumpShip>,
+        #[structopt(long = "fatigue", requires = "ship")]
+        fatigue: Option<f64>,
+        #[structopt(short = "f", long = "fastest", requires = "ship")]
+        fastest: bool,
+        #[structopt(short = "g", long = "gates", conflicts_with = "ship")]
+        gates: bool,
+        #[structopt(long = "jump-cost", requires = "gates", parse(try_from_str = parse_cost))]
+        jump_cost: Option<f64>,
+        #[structopt(long = "ly-cost", requires = "gates", parse(try_from_str = parse_cost))]
+        ly_cost: Option<f64>,
+        #[structopt(long = "avoid", number_of_values = 1)]
+        avoid: Vec<String>,
         #[structopt(name = "START")]
         start: String,
         #[structopt(name = "GOAL")]
         goal: String,
     },
+    Tour {
+        #[structopt(short = "e", long = "end")]
+        end: Option<String>,
+        #[structopt(name = "START")]
+        start: String,
+        #[structopt(name = "STOPS", required = true)]
+        stops: Vec<String>,
+    },
 }
 
-// Look up the given system name in the map, and panic if
-// not found. This should be cleaned up.
-fn find_system(map: &Map, name: &str) -> SystemId {
-    map.by_name(name)
-        .unwrap_or_else(|| panic!("could not find {} in map", name))
-        .system_id
+// Parse a route cost, which must be finite and not negative.
+fn parse_cost(s: &str) -> Result<f64, String> {
+    match s.parse::<f64>() {
+        Ok(cost) if cost.is_finite() && cost >= 0.0 => Ok(cost),
+        _ => Err(format!("bad cost {}: must be a number of at least 0", s)),
+    }
 }
 
-// Find a shortest route by name, or panic if none exists.
-fn find_route(map: &Map, start: &str, goal: &str) -> Vec<SystemId> {
-    let start_id = find_system(map, start);
-    let goal_id = find_system(map, goal);
-    shortest_route(map, start_id, goal_id)
-        .unwrap_or_else(|| panic!("no route found from {} to {}", start, goal))
+// Look up the given system name in the map.
+fn find_system(map: &Map, name: &str) -> Result<SystemId, Error> {
+    Ok(map.try_by_name(name)?.system_id)
 }
 
-// Find all shortest routes by name, or panic if none exists.
-fn find_all_routes(map: &Map, start: &str, goal: &str) -> Vec<Vec<SystemId>> {
-    let start_id = find_system(map, start);
-    let goal_id = find_system(map, goal);
-    let apsp = apsp(map);
-    shortest_routes_apsp(map, &apsp, start_id, goal_id)
-        .unwrap_or_else(|| panic!("no route found from {} to {}", start, goal))
+// Build a route request from system names.
+fn make_request(
+    map: &Map,
+    start: &str,
+    via: &[String],
+    goal: &str,
+    prefer: Preference,
+    penalty: usize,
+    avoid: &[String],
+) -> Result<RouteRequest, Error> {
+    let mut request = RouteRequest::new(find_system(map, start)?, find_system(map, goal)?);
+    request.waypoints = via
+        .iter()
+        .map(|name| find_system(map, name))
+        .collect::<Result<_, _>>()?;
+    request.preference = prefer;
+    request.penalty = penalty;
+    for name in avoid {
+        request.avoid.insert_name(map, name)?;
+    }
+    Ok(request)
+}
+
+// Find a best route for the request.
+fn find_route(map: &Map, request: &RouteRequest) -> Result<WaypointRoute, Error> {
+    request.route(map)
+}
+
+// Find all shortest routes for the request, ignoring
+// waypoints and preference, one at a time.
+fn find_all_routes<'a>(
+    map: &'a Map,
+    apsp: &'a APSPTable,
+    request: &RouteRequest,
+) -> Result<ShortestRoutes<'a>, Error> {
+    iter_shortest_routes(map, apsp, request.start, request.goal, &request.avoid)
+}
+
+// Find up to `k` best loopless routes for the request,
+// ignoring waypoints.
+fn find_k_routes(map: &Map, request: &RouteRequest, k: usize) -> Result<Vec<Vec<SystemId>>, Error> {
+    let cost = SecurityCost {
+        preference: request.preference,
+        penalty: request.penalty,
+    };
+    let routes = k_shortest_routes(map, request.start, request.goal, k, &cost, &request.avoid)?;
+    Ok(routes.into_iter().map(|r| r.route).collect())
+}
+
+// Exit status for an error: 1 for no route, 2 for a bad
+// system name or id, 3 for unusable map data.
+fn exit_code(e: &Error) -> i32 {
+    match e {
+        Error::Unreachable { .. } => 1,
+        Error::UnknownSystemName(_) | Error::UnknownSystemId(_) => 2,
+        _ => 3,
+    }
+}
+
+// Report the error, naming systems where the map allows,
+// and exit with the matching status.
+fn fail(map: Option<&Map>, e: Error) -> ! {
+    let message = match (map, &e) {
+        (
+            Some(map),
+            Error::Unreachable {
+                start,
+                goal,
+                avoided,
+            },
+        ) => {
+            let start = &map.by_system_id(*start).name;
+            let goal = &map.by_system_id(*goal).name;
+            let avoiding = if *avoided {
+                " avoiding the given systems"
+            } else {
+                ""
+            };
+            format!("no route found from {} to {}{}", start, goal, avoiding)
+        }
+        _ => e.to_string(),
+    };
+    eprintln!("plan-b: {}", message);
+    std::process::exit(exit_code(&e))
 }
 
 #[test]
 // Check for correct computation of a long route.
 fn short_route_north_south() {
     let map = Map::fetch().expect("could not open map");
-    let route = find_route(&map, "B-GC1T", "2UK4-N");
+    let request =
+        make_request(&map, "B-GC1T", &[], "2UK4-N", Preference::Shortest, 0, &[]).unwrap();
+    let route = find_route(&map, &request).unwrap().route;
     assert_eq!(80, route.len());
 }
 
-// Display a given route, one system per line.
+#[test]
+// Check that the safer route from Jita to Amarr stays in
+// high-sec, unlike the shortest.
+fn safer_route_jita_amarr() {
+    let map = Map::fetch().expect("could not open map");
+    let high = |route: &[SystemId]| {
+        route
+            .iter()
+            .all(|s| map.by_system_id(*s).security_band() == SecurityBand::High)
+    };
+    let request = make_request(&map, "Jita", &[], "Amarr", Preference::Shortest, 0, &[]).unwrap();
+    let shortest = find_route(&map, &request).unwrap().route;
+    assert!(!high(&shortest));
+    let request = make_request(
+        &map,
+        "Jita",
+        &[],
+        "Amarr",
+        Preference::Safer,
+        DEFAULT_PENALTY,
+        &[],
+    )
+    .unwrap();
+    let safer = find_route(&map, &request).unwrap().route;
+    assert!(high(&safer));
+    assert!(safer.len() > shortest.len());
+}
+
+#[test]
+// Check that bad names and blocked routes map to distinct
+// exit statuses.
+fn route_errors() {
+    let map = Map::fetch().expect("could not open map");
+    let e = make_request(&map, "Jita", &[], "Nowhere", Preference::Shortest, 0, &[]).unwrap_err();
+    assert!(matches!(e, Error::UnknownSystemName(ref name) if name == "Nowhere"));
+    assert_eq!(2, exit_code(&e));
+    let avoid = vec!["Perimeter".to_string()];
+    let request = make_request(&map, "Jita", &[], "Amarr", Preference::Shortest, 0, &avoid);
+    assert!(find_route(&map, &request.unwrap()).is_ok());
+    let jita = map.by_name("Jita").unwrap();
+    let avoid: Vec<String> = jita
+        .stargates
+        .iter()
+        .map(|s| map.by_system_id(*s).name.clone())
+        .collect();
+    let request = make_request(&map, "Jita", &[], "Amarr", Preference::Shortest, 0, &avoid);
+    let e = find_route(&map, &request.unwrap()).unwrap_err();
+    assert!(matches!(e, Error::Unreachable { avoided: true, .. }));
+    assert_eq!(1, exit_code(&e));
+}
+
+// Word marking a hop through the given kind of gate.
+fn gate_word(kind: GateKind) -> &'static str {
+    match kind {
+        GateKind::Stargate => "gate",
+        GateKind::Bridge => "bridge",
+        GateKind::Wormhole => "wormhole",
+    }
+}
+
+#[test]
+// Check that the jump subcommand's options parse without
+// `--gates`, and that the cost options need it.
+fn jump_args() {
+    let parse = |args: &str| Args::from_iter_safe(args.split_whitespace());
+    let args = parse("plan-b jump --range 5 A B").unwrap();
+    assert!(matches!(args.opt, Opt::Jump { gates: false, .. }));
+    let args = parse("plan-b jump --ship capital --fastest --range 5 A B").unwrap();
+    assert!(matches!(
+        args.opt,
+        Opt::Jump {
+            ship: Some(JumpShip::Capital),
+            fastest: true,
+            ..
+        }
+    ));
+    let args = parse("plan-b jump --gates --jump-cost 3 --range 5 A B").unwrap();
+    assert!(matches!(
+        args.opt,
+        Opt::Jump {
+            jump_cost: Some(_),
+            ly_cost: None,
+            ..
+        }
+    ));
+    assert!(parse("plan-b jump --jump-cost 3 --range 5 A B").is_err());
+    assert!(parse("plan-b jump --gates --jump-cost=-1 --range 5 A B").is_err());
+    assert!(parse("plan-b jump --gates --ly-cost NaN --range 5 A B").is_err());
+}
+
+#[test]
+// Check that subcommands showing stops that are not next to
+// each other run.
+fn sparse_and_tour() {
+    let map = Map::fetch().expect("could not open map");
+    let run_args = |args: &str| {
+        let args = Args::from_iter_safe(args.split_whitespace()).unwrap();
+        run(&map, args.opt, &|| apsp(&map))
+    };
+    run_args("plan-b route --sparse Jita Amarr").unwrap();
+    run_args("plan-b route --sparse --via Dodixie Jita Amarr").unwrap();
+    run_args("plan-b tour Jita Amarr Dodixie Rens").unwrap();
+}
+
+// Display a given route, one system per line, marking
+// systems entered by jump bridge or wormhole.
 fn show_route(map: &Map, route: &[SystemId]) {
-    for system_id in route {
-        let system = map.by_system_id(*system_id);
-        println!("{}", system.name);
+    show_marked_route(map, route, &[]);
+}
+
+// Display the given stops, one system per line. Unlike
+// `show_route()`, the stops need not be next to each other.
+fn show_stops(map: &Map, stops: &[SystemId]) {
+    for system_id in stops {
+        println!("{}", map.by_system_id(*system_id).name);
+    }
+}
+
+// Display a given route as `show_route()` does, also
+// marking the systems at the given route indices as
+// waypoints.
+fn show_marked_route(map: &Map, route: &[SystemId], waypoints: &[usize]) {
+    let kinds = gate_kinds(map, route).unwrap_or_default();
+    for (i, system_id) in route.iter().enumerate() {
+        let mut line = map.by_system_id(*system_id).name.clone();
+        match i.checked_sub(1).and_then(|hop| kinds.get(hop)) {
+            None | Some(GateKind::Stargate) => (),
+            Some(&kind) => line = format!("{} {}", line, gate_word(kind)),
+        }
+        if waypoints.contains(&i) {
+            line.push_str(" waypoint");
+        }
+        println!("{}", line);
     }
 }
 
 // Command-line Plan B. */
 fn main() {
-    // Set up the map.
-    let map = Map::fetch().expect("could not open map");
+    // Get the arguments and find the map.
+    let args = Args::from_args();
+    let map_file = match args.map {
+        Some(path) => path,
+        None => map_path().unwrap_or_else(|e| fail(None, e)),
+    };
+
+    // Compiling the map cache needs no map.
+    if let Opt::CompileMap = args.opt {
+        let cache = Map::compile_cache(&map_file).unwrap_or_else(|e| fail(None, e));
+        println!("{}", cache.display());
+        return;
+    }
 
-    // Get and process the arguments.
-    let opt = Opt::from_args();
+    // Set up the map, with any jump bridges and wormholes.
+    let mut map = Map::from_path(&map_file).unwrap_or_else(|e| fail(None, e));
 
-    // Just do diameter.
+    // The saved APSP table is for the map alone, so that
+    // overlays never replace it.
+    if let Opt::CompileApsp = args.opt {
+        let path = apsp_path(&map_file);
+        let apsp = apsp_threads(&map, args.threads);
+        save_apsp(&map, &apsp, &path).unwrap_or_else(|e| fail(None, e));
+        println!("{}", path.display());
+        return;
+    }
+    if let Some(path) = args.bridges {
+        let alliances = if args.alliance.is_empty() {
+            None
+        } else {
+            Some(&args.alliance[..])
+        };
+        load_bridges(path)
+            .and_then(|bridges| map.add_bridges(&bridges, alliances))
+            .unwrap_or_else(|e| fail(Some(&map), e));
+    }
+    if let Some(path) = args.wormholes {
+        let now = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map_or(0, |t| t.as_secs());
+        load_wormholes(path)
+            .and_then(|wormholes| map.add_wormholes(&wormholes, now))
+            .unwrap_or_else(|e| fail(Some(&map), e));
+        map.remove_unusable(now, args.ship_size);
+    }
+
+    // Process the arguments, reusing the saved APSP table
+    // if there is one for this map.
+    let apsp = || cached_apsp(&map, apsp_path(&map_file), args.threads);
+    if let Err(e) = run(&map, args.opt, &apsp) {
+        fail(Some(&map), e);
+    }
+}
+
+// Carry out the command given by the arguments, getting the
+// APSP table from `apsp` if needed.
+fn run(map: &Map, opt: Opt, apsp: &dyn Fn() -> APSPTable) -> Result<(), Error> {
     match opt {
+        Opt::CompileMap => unreachable!("map cache compiled before loading map"),
+        Opt::CompileApsp => unreachable!("APSP table compiled before loading overlays"),
         Opt::Diameter => {
             // Run the diameter calculation and display the result.
-            let diameter_info = diameter(&map);
+            let diameter_info = diameter(map, &apsp());
             println!("diameter {}", diameter_info.diameter);
             for (start, end) in diameter_info.longest {
                 let start = &map.by_system_id(start).name;
@@ -87,21 +416,142 @@ fn main() {
                 println!("{} → {}", start, end);
             }
         }
-        Opt::Route { all, start, goal } => {
+        Opt::Route {
+            all,
+            count,
+            limit,
+            k,
+            sparse,
+            prefer,
+            penalty,
+            avoid,
+            via,
+            start,
+            goal,
+        } => {
+            let request = make_request(map, &start, &via, &goal, prefer, penalty, &avoid)?;
             // Show all routes.
             if all {
-                let mut routes = find_all_routes(&map, &start, &goal);
-                let last = routes.pop().unwrap();
-                for route in routes {
-                    show_route(&map, &route);
-                    println!();
+                let apsp = apsp();
+                if count {
+                    let (start, goal) = (request.start, request.goal);
+                    let n = count_shortest_routes(map, &apsp, start, goal, &request.avoid)?;
+                    println!("{}", n);
+                    return Ok(());
+                }
+                let routes = find_all_routes(map, &apsp, &request)?;
+                for (i, route) in routes.take(limit.unwrap_or(usize::MAX)).enumerate() {
+                    if i > 0 {
+                        println!();
+                    }
+                    show_route(map, &route);
+                }
+                return Ok(());
+            }
+            // Show the k best routes.
+            if let Some(k) = k {
+                for (i, route) in find_k_routes(map, &request, k)?.iter().enumerate() {
+                    if i > 0 {
+                        println!();
+                    }
+                    show_route(map, route);
                 }
-                show_route(&map, &last);
-                return;
+                return Ok(());
             }
             // Get the destination, find the route and display it.
-            let route = find_route(&map, &start, &goal);
-            show_route(&map, &route);
+            let route = find_route(map, &request)?;
+            if sparse {
+                // Show just the waypoints the autopilot needs.
+                let waypoints =
+                    sparse_waypoints(map, &route.route, prefer, penalty, &request.avoid);
+                let mut stops = vec![request.start];
+                stops.extend(waypoints);
+                stops.push(request.goal);
+                show_stops(map, &stops);
+                return Ok(());
+            }
+            show_marked_route(map, &route.route, route.waypoints());
+        }
+        Opt::Jump {
+            range,
+            ship,
+            fatigue,
+            fastest,
+            gates,
+            jump_cost,
+            ly_cost,
+            avoid,
+            start,
+            goal,
+        } => {
+            // Find the jump drive route and display each jump's
+            // length, and with a ship its time.
+            let start = find_system(map, &start)?;
+            let goal = find_system(map, &goal)?;
+            let mut avoid_set = Avoid::new();
+            for name in &avoid {
+                avoid_set.insert_name(map, name)?;
+            }
+            if gates {
+                // Show how each system is entered.
+                let default = MixedCost::default();
+                let cost = MixedCost {
+                    jump: jump_cost.unwrap_or(default.jump),
+                    light_year: ly_cost.unwrap_or(default.light_year),
+                    ..default
+                };
+                let mixed = mixed_route(map, start, goal, range, &cost, &avoid_set)?;
+                println!("{}", map.by_system_id(start).name);
+                for (hop, kind) in mixed.route.windows(2).zip(&mixed.hops) {
+                    let (from, to) = (map.by_system_id(hop[0]), map.by_system_id(hop[1]));
+                    match kind {
+                        Hop::Gate(kind) => println!("{} {}", to.name, gate_word(*kind)),
+                        Hop::Jump => println!("{} jump {:.2} ly", to.name, light_years(from, to)),
+                    }
+                }
+                return Ok(());
+            }
+            let fatigue = fatigue.unwrap_or(0.0);
+            let Some(ship) = ship else {
+                let jumps = jump_route(map, start, goal, range, &avoid_set)?;
+                println!("{}", map.by_system_id(start).name);
+                for (system_id, ly) in jumps.route[1..].iter().zip(&jumps.distances) {
+                    println!("{} {:.2} ly", map.by_system_id(*system_id).name, ly);
+                }
+                return Ok(());
+            };
+            let model = FatigueModel::for_ship(ship);
+            let jumps = if fastest {
+                fastest_jump_route(map, start, goal, range, &model, fatigue, &avoid_set)?
+            } else {
+                jump_route(map, start, goal, range, &avoid_set)?
+            };
+            let schedule = model.schedule(&jumps, fatigue);
+            println!("elapsed {:.1} min", schedule.elapsed());
+            println!("fatigue {:.1} min", schedule.final_fatigue());
+            println!("{}", map.by_system_id(start).name);
+            let hops = jumps.route[1..].iter().zip(&jumps.distances);
+            for ((system_id, ly), departure) in hops.zip(&schedule.departures) {
+                let name = &map.by_system_id(*system_id).name;
+                println!("{} {:.2} ly at {:.1} min", name, ly, departure);
+            }
+        }
+        Opt::Tour { end, start, stops } => {
+            // Order the stops and display the visiting order.
+            let start_id = find_system(map, &start)?;
+            let end_id = end.map(|name| find_system(map, &name)).transpose()?;
+            let stop_ids = stops
+                .iter()
+                .map(|name| find_system(map, name))
+                .collect::<Result<Vec<_>, _>>()?;
+            let apsp = apsp();
+            let tour = tour(map, &apsp, start_id, &stop_ids, end_id)?;
+            println!("jumps {}", tour.jumps);
+            let mut visits = vec![start_id];
+            visits.extend(tour.stops);
+            visits.extend(end_id);
+            show_stops(map, &visits);
         }
     }
+    Ok(())
 }
@@ -73,6 +73,10 @@ def ccp_request(path):
 by_system_id = dict()
 by_stargate_id = dict()
 
+# Map of retrieved regions and constellations.
+by_region_id = dict()
+by_constellation_id = dict()
+
 # A thread worker.
 def worker(systems):
     "Fetch the given systems' information via ESI."
@@ -103,21 +107,61 @@ def worker(systems):
     for stargate_id, stargate in tls.by_stargate_id.items():
         by_stargate_id[stargate_id] = stargate
 
+# A region thread worker.
+def region_worker(regions):
+    "Fetch the given regions' information via ESI."
+    global by_region_id, by_constellation_id
+    tls.connection = client.HTTPSConnection(esi_endpoint)
+    tls.by_region_id = dict()
+    tls.by_constellation_id = dict()
+
+    # Grab the regions.
+    for region_id in regions:
+        region = ccp_request('universe/regions/' + str(region_id))
+        log(region['name'])
+        tls.by_region_id[region_id] = region
+
+    # Grab the constellations for each region.
+    for region_id, region in tls.by_region_id.items():
+        for constellation_id in region['constellations']:
+            constellation = ccp_request('universe/constellations/' +
+                                        str(constellation_id))
+            log(region['name'], "->", constellation['name'])
+            tls.by_constellation_id[constellation_id] = constellation
+
+    # Move region and constellation information to the global map.
+    for region_id, region in tls.by_region_id.items():
+        by_region_id[region_id] = region
+    for constellation_id, constellation in tls.by_constellation_id.items():
+        by_constellation_id[constellation_id] = constellation
+
 # Open the master connection and get a list of systems.
 tls.connection = client.HTTPSConnection(esi_endpoint)
 systems = ccp_request('universe/systems')
 nsystems = len(systems)
 log(nsystems, "systems")
 
+# Get a list of regions.
+regions = ccp_request('universe/regions')
+nregions = len(regions)
+log(nregions, "regions")
+
 # Start and collect the threads.
 threads = [threading.Thread(target=worker, args=(chunk,))
            for chunk in chunks(systems, nthreads)]
+threads += [threading.Thread(target=region_worker, args=(chunk,))
+            for chunk in chunks(regions, nthreads)]
 for t in threads:
     t.start()
 for t in threads:
     t.join()
 
 # Write the output JSON.
-info = {'systems': by_system_id, 'stargates': by_stargate_id}
+info = {
+    'systems': by_system_id,
+    'stargates': by_stargate_id,
+    'constellations': by_constellation_id,
+    'regions': by_region_id,
+}
 with open('eve-map.json', 'w') as dumpfile:
     json.dump(info, dumpfile)
@@ -0,0 +1,156 @@
+// Copyright © 2018 Po Huit
+// [This program is licensed under the "MIT License"]
+// Please see the file LICENSE in the source
+// distribution of this software for license terms.
+
+//! Player-built jump bridge (Ansiblex) overlays for Plan B.
+//!
+//! Jump bridges are not in the ESI map data. An overlay file
+//! lists them as JSON:
+//!
+//! ```text
+//! [
+//!     {"from": "1DQ1-A", "to": "8WA-Z6", "alliance": "Goonswarm Federation"},
+//!     {"from": "Jita", "to": "Perimeter"}
+//! ]
+//! ```
+//!
+//! Each bridge works both ways. Once added to the map,
+//! bridges are gates like any other, so every search can use
+//! them.
+
+use std::fs;
+use std::io::{BufReader, Read};
+use std::path::Path;
+
+use serde::Deserialize;
+
+use crate::error::*;
+use crate::map::*;
+
+/// A jump bridge between two systems, by name.
+#[derive(Clone, Debug, PartialEq, Eq, Deserialize)]
+pub struct Bridge {
+    /// Name of one system.
+    pub from: String,
+    /// Name of the other system.
+    pub to: String,
+    /// Alliance owning the bridge, if known.
+    #[serde(default)]
+    pub alliance: Option<String>,
+}
+
+/// Parse a jump bridge overlay from the given reader.
+pub fn read_bridges<R: Read>(reader: R) -> Result<Vec<Bridge>, Error> {
+    Ok(serde_json::from_reader(reader)?)
+}
+
+/// Read the jump bridge overlay file at the given path.
+pub fn load_bridges<P: AsRef<Path>>(path: P) -> Result<Vec<Bridge>, Error> {
+    let file = fs::File::open(path).map_err(Error::Io)?;
+    read_bridges(BufReader::new(file))
+}
+
+impl Map {
+    /// Add the given jump bridges to the map, both ways, as
+    /// gates of kind `GateKind::Bridge` at the star of each
+    /// system. If `alliances` is given, only bridges owned by
+    /// one of them are added. Bridges already in the map are
+    /// skipped. Returns the number of bridges added; if a
+    /// system name is unknown, nothing is added.
+    pub fn add_bridges(
+        &mut self,
+        bridges: &[Bridge],
+        alliances: Option<&[String]>,
+    ) -> Result<usize, Error> {
+        let mut ends = Vec::new();
+        for bridge in bridges {
+            let allowed = match (alliances, &bridge.alliance) {
+                (None, _) => true,
+                (Some(alliances), Some(alliance)) => alliances.contains(alliance),
+                (Some(_), None) => false,
+            };
+            if allowed {
+                let from = self.try_by_name(&bridge.from)?.system_index;
+                let to = self.try_by_name(&bridge.to)?.system_index;
+                ends.push(Link {
+                    from,
+                    to,
+                    expires: None,
+                    size: None,
+                });
+            }
+        }
+        Ok(self.add_links(&ends, GateKind::Bridge))
+    }
+}
+
+/// Kind of gate taken for each hop of the given route, or
+/// `None` if some hop does not follow a gate of the map.
+/// Where several kinds of gate lead to the same system, the
+/// first kind in `GateKind` order is taken.
+pub fn gate_kinds(map: &Map, route: &[SystemId]) -> Option<Vec<GateKind>> {
+    route
+        .windows(2)
+        .map(|hop| {
+            let gates = &map.try_by_system_id(hop[0]).ok()?.gates;
+            gates
+                .iter()
+                .filter(|g| g.destination == hop[1])
+                .map(|g| g.kind)
+                .min()
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::search::*;
+    use crate::testing::*;
+
+    const BRIDGES: &str = r#"[
+        {"from": "Alpha", "to": "Gamma", "alliance": "Test Alliance"},
+        {"from": "Gamma", "to": "Alpha"},
+        {"from": "Beta", "to": "Gamma", "alliance": "Other Alliance"}
+    ]"#;
+
+    #[test]
+    fn tiny_bridges() {
+        let bridges = read_bridges(BRIDGES.as_bytes()).unwrap();
+        assert_eq!(3, bridges.len());
+        assert_eq!(None, bridges[1].alliance);
+
+        // Only the first bridge belongs to the allowed alliance.
+        let mut map = tiny_map();
+        let id = |map: &Map, name| find_system(map, name);
+        let allowed = ["Test Alliance".to_string()];
+        assert_eq!(1, map.add_bridges(&bridges, Some(&allowed)).unwrap());
+        let route = shortest_route(&map, id(&map, "Gamma"), id(&map, "Alpha"), &Avoid::new());
+        let route = route.unwrap();
+        assert_eq!(2, route.len());
+        assert_eq!(vec![GateKind::Bridge], gate_kinds(&map, &route).unwrap());
+
+        // The second bridge duplicates the first. Beta to
+        // Gamma also has a stargate, which is preferred.
+        assert_eq!(1, map.add_bridges(&bridges, None).unwrap());
+        let beta = map.by_name("Beta").unwrap();
+        assert_eq!(3, beta.gates.len());
+        let route = [id(&map, "Beta"), id(&map, "Gamma")];
+        assert_eq!(vec![GateKind::Stargate], gate_kinds(&map, &route).unwrap());
+
+        // Without the bridge, Alpha is not next to Gamma.
+        let plain = tiny_map();
+        let route = [id(&plain, "Alpha"), id(&plain, "Gamma")];
+        assert_eq!(None, gate_kinds(&plain, &route));
+
+        let unknown = [Bridge {
+            from: "Alpha".to_string(),
+            to: "Nowhere".to_string(),
+            alliance: None,
+        }];
+        let e = map.add_bridges(&unknown, None).unwrap_err();
+        assert!(matches!(e, Error::UnknownSystemName(_)));
+        assert_eq!(2, map.by_name("Alpha").unwrap().gates.len());
+    }
+}
@@ -0,0 +1,670 @@
+// Copyright © 2018 Po Huit
+// [This program is licensed under the "MIT License"]
+// Please see the file LICENSE in the source
+// distribution of this software for license terms.
+
+//! Compiled binary caches of map data and APSP tables for
+//! Plan B.
+//!
+//! Parsing the JSON map data takes seconds; loading the
+//! compiled cache takes milliseconds. All numbers are
+//! little-endian. The cache is laid out as
+//!
+//! * Header: the magic bytes `CACHE_MAGIC`, the format
+//!   version (`u32`), a hash of the map file the cache was
+//!   compiled from (`u64`), and the length (`u64`) and hash
+//!   (`u64`) of the payload.
+//! * Payload: an interned string table; the systems in
+//!   `system_index` order; their stargates in compressed
+//!   sparse row form, with destinations as system indices, a
+//!   code for the kind of gate, its expiry (`u64::MAX` if
+//!   none) and a code for the largest ship it passes (0 if
+//!   unlimited);
+//!   then the constellations and the regions.
+//!
+//! A cache is stale if its version or its source hash does
+//! not match; stale caches are never loaded in place of their
+//! map file.
+//!
+//! APSP tables are saved in a similar file: the magic bytes
+//! `APSP_MAGIC`, the format version (`u32`), the
+//! `topology_hash()` of the map (`u64`), the number of
+//! systems (`u64`) and a hash of the distances (`u64`),
+//! followed by the distances as a row-major `u16` matrix.
+
+use std::collections::HashMap;
+use std::fs;
+use std::io::{BufReader, Read, Write};
+use std::path::{Path, PathBuf};
+
+use crate::error::*;
+use crate::map::*;
+use crate::search::*;
+use crate::wormholes::*;
+
+/// Magic bytes at the start of a compiled map cache.
+pub const CACHE_MAGIC: &[u8; 8] = b"PLANBMAP";
+
+/// Version of the compiled map cache format.
+pub const CACHE_VERSION: u32 = 6;
+
+/// Magic bytes at the start of a saved APSP table.
+pub const APSP_MAGIC: &[u8; 8] = b"PLANBAPS";
+
+/// Version of the saved APSP table format.
+pub const APSP_VERSION: u32 = 1;
+
+/// Path of the compiled map cache for the given map file:
+/// the map file path with its last extension replaced by
+/// `bin`.
+pub fn cache_path<P: AsRef<Path>>(source: P) -> PathBuf {
+    source.as_ref().with_extension("bin")
+}
+
+/// Path of the saved APSP table for the given map file: the
+/// map file path with its last extension replaced by `apsp`.
+pub fn apsp_path<P: AsRef<Path>>(source: P) -> PathBuf {
+    source.as_ref().with_extension("apsp")
+}
+
+// 64-bit FNV-1a hash.
+fn fnv1a(bytes: &[u8]) -> u64 {
+    let mut hash: u64 = 0xcbf2_9ce4_8422_2325;
+    for b in bytes {
+        hash ^= *b as u64;
+        hash = hash.wrapping_mul(0x0000_0100_0000_01b3);
+    }
+    hash
+}
+
+// Error for an unusable cache.
+fn bad(why: &str) -> Error {
+    Error::BadCache(why.to_string())
+}
+
+// Little-endian payload writer.
+struct Writer {
+    buf: Vec<u8>,
+}
+
+impl Writer {
+    fn u32(&mut self, v: usize) -> Result<(), Error> {
+        let v = u32::try_from(v).map_err(|_| bad("value too large"))?;
+        self.buf.extend(v.to_le_bytes());
+        Ok(())
+    }
+
+    fn u64(&mut self, v: u64) {
+        self.buf.extend(v.to_le_bytes());
+    }
+
+    fn f64(&mut self, v: f64) {
+        self.buf.extend(v.to_le_bytes());
+    }
+
+    fn u32s<I: IntoIterator<Item = usize>>(&mut self, vs: I) -> Result<(), Error> {
+        let vs: Vec<usize> = vs.into_iter().collect();
+        self.u32(vs.len())?;
+        for v in vs {
+            self.u32(v)?;
+        }
+        Ok(())
+    }
+}
+
+// Little-endian payload reader.
+struct Reader<'a> {
+    buf: &'a [u8],
+}
+
+impl<'a> Reader<'a> {
+    fn take(&mut self, n: usize) -> Result<&'a [u8], Error> {
+        if self.buf.len() < n {
+            return Err(bad("truncated"));
+        }
+        let (bytes, rest) = self.buf.split_at(n);
+        self.buf = rest;
+        Ok(bytes)
+    }
+
+    fn u32(&mut self) -> Result<usize, Error> {
+        let bytes = self.take(4)?.try_into().unwrap();
+        Ok(u32::from_le_bytes(bytes) as usize)
+    }
+
+    fn u64(&mut self) -> Result<u64, Error> {
+        let bytes = self.take(8)?.try_into().unwrap();
+        Ok(u64::from_le_bytes(bytes))
+    }
+
+    fn f64(&mut self) -> Result<f64, Error> {
+        let bytes = self.take(8)?.try_into().unwrap();
+        Ok(f64::from_le_bytes(bytes))
+    }
+
+    fn u32s(&mut self) -> Result<Vec<usize>, Error> {
+        let n = self.u32()?;
+        (0..n).map(|_| self.u32()).collect()
+    }
+}
+
+// Table of distinct strings, referred to by index.
+#[derive(Default)]
+struct Strings<'a> {
+    strings: Vec<&'a str>,
+    index: HashMap<&'a str, usize>,
+}
+
+impl<'a> Strings<'a> {
+    fn intern(&mut self, s: &'a str) -> usize {
+        if let Some(&i) = self.index.get(s) {
+            return i;
+        }
+        self.strings.push(s);
+        self.index.insert(s, self.strings.len() - 1);
+        self.strings.len() - 1
+    }
+}
+
+impl Map {
+    /// Write a compiled cache of this map, recording `source`
+    /// as the contents of the map file it was compiled from.
+    pub fn write_cache<W: Write>(&self, mut writer: W, source: &[u8]) -> Result<(), Error> {
+        // Intern all the names.
+        let systems = self.systems_ref();
+        let mut constellations: Vec<&ConstellationInfo> = self.constellations().collect();
+        constellations.sort_by_key(|c| c.constellation_id);
+        let mut regions: Vec<&RegionInfo> = self.regions().collect();
+        regions.sort_by_key(|r| r.region_id);
+        let mut strings = Strings::default();
+        let system_names: Vec<usize> = systems.iter().map(|s| strings.intern(&s.name)).collect();
+        let constellation_names: Vec<usize> = constellations
+            .iter()
+            .map(|c| strings.intern(&c.name))
+            .collect();
+        let region_names: Vec<usize> = regions.iter().map(|r| strings.intern(&r.name)).collect();
+
+        // Write the payload.
+        let mut w = Writer { buf: Vec::new() };
+        w.u32(strings.strings.len())?;
+        let mut offset = 0;
+        for s in &strings.strings {
+            offset += s.len();
+            w.u32(offset)?;
+        }
+        for s in &strings.strings {
+            w.buf.extend(s.as_bytes());
+        }
+        w.u32(systems.len())?;
+        for (system, name) in systems.iter().zip(system_names) {
+            w.u32(system.system_id.0)?;
+            w.u32(name)?;
+            w.u32(system.constellation_id.0)?;
+            w.f64(system.security_status);
+            w.f64(system.position.x);
+            w.f64(system.position.y);
+            w.f64(system.position.z);
+        }
+        let mut offset = 0;
+        for system in systems {
+            offset += system.gates.len();
+            w.u32(offset)?;
+        }
+        for gate in systems.iter().flat_map(|s| s.gates.iter()) {
+            w.u32(self.by_system_id(gate.destination).system_index)?;
+            w.f64(gate.position.x);
+            w.f64(gate.position.y);
+            w.f64(gate.position.z);
+            w.u32(match gate.kind {
+                GateKind::Stargate => 0,
+                GateKind::Bridge => 1,
+                GateKind::Wormhole => 2,
+            })?;
+            w.u64(gate.expires.unwrap_or(u64::MAX));
+            w.u32(match gate.size {
+                None => 0,
+                Some(WormholeSize::Small) => 1,
+                Some(WormholeSize::Medium) => 2,
+                Some(WormholeSize::Large) => 3,
+                Some(WormholeSize::VeryLarge) => 4,
+            })?;
+        }
+        w.u32(constellations.len())?;
+        for (c, name) in constellations.iter().zip(constellation_names) {
+            w.u32(c.constellation_id.0)?;
+            w.u32(name)?;
+            w.u32(c.region_id.0)?;
+            w.u32s(c.systems.iter().map(|s| s.0))?;
+        }
+        w.u32(regions.len())?;
+        for (r, name) in regions.iter().zip(region_names) {
+            w.u32(r.region_id.0)?;
+            w.u32(name)?;
+            w.u32s(r.constellations.iter().map(|c| c.0))?;
+        }
+        let payload = w.buf;
+
+        // Write the header, then the payload.
+        let mut header = Vec::with_capacity(36);
+        header.extend(CACHE_MAGIC);
+        header.extend(CACHE_VERSION.to_le_bytes());
+        header.extend(fnv1a(source).to_le_bytes());
+        header.extend((payload.len() as u64).to_le_bytes());
+        header.extend(fnv1a(&payload).to_le_bytes());
+        writer.write_all(&header).map_err(Error::Io)?;
+        writer.write_all(&payload).map_err(Error::Io)?;
+        Ok(())
+    }
+
+    /// Compile the map file at the given path into a cache at
+    /// `cache_path()`, returning the cache path.
+    pub fn compile_cache<P: AsRef<Path>>(source: P) -> Result<PathBuf, Error> {
+        let source = source.as_ref();
+        let bytes = read_map_file(source)?;
+        let map = Map::from_reader(&bytes[..])?;
+        let mut cache = Vec::new();
+        map.write_cache(&mut cache, &bytes)?;
+        let path = cache_path(source);
+        fs::write(&path, cache).map_err(Error::Io)?;
+        Ok(path)
+    }
+
+    /// Load a compiled map cache. If `source` is given, the
+    /// cache must have been compiled from it.
+    pub fn from_cache<R: Read>(mut reader: R, source: Option<&[u8]>) -> Result<Map, Error> {
+        let mut bytes = Vec::new();
+        reader.read_to_end(&mut bytes).map_err(Error::Io)?;
+        let mut r = Reader { buf: &bytes };
+
+        // Check the header.
+        if r.take(CACHE_MAGIC.len())? != CACHE_MAGIC {
+            return Err(bad("not a map cache"));
+        }
+        if r.u32()? != CACHE_VERSION as usize {
+            return Err(bad("wrong version"));
+        }
+        let source_hash = r.u64()?;
+        if source.map(fnv1a).unwrap_or(source_hash) != source_hash {
+            return Err(bad("stale"));
+        }
+        let len = r.u64()?;
+        let hash = r.u64()?;
+        if r.buf.len() as u64 != len || fnv1a(r.buf) != hash {
+            return Err(bad("checksum mismatch"));
+        }
+
+        // Read the string table.
+        let n_strings = r.u32()?;
+        let ends: Vec<usize> = (0..n_strings).map(|_| r.u32()).collect::<Result<_, _>>()?;
+        let text = r.take(ends.last().cloned().unwrap_or(0))?;
+        let text = std::str::from_utf8(text).map_err(|_| bad("bad string table"))?;
+        let mut strings = Vec::with_capacity(n_strings);
+        let mut start = 0;
+        for end in ends {
+            strings.push(
+                text.get(start..end)
+                    .ok_or_else(|| bad("bad string table"))?,
+            );
+            start = end;
+        }
+        let string = |i: usize| -> Result<String, Error> {
+            strings
+                .get(i)
+                .map(|s| s.to_string())
+                .ok_or_else(|| bad("bad string index"))
+        };
+
+        // Read the systems and their stargates.
+        let n = r.u32()?;
+        let mut systems = Vec::with_capacity(n);
+        for system_index in 0..n {
+            systems.push(SystemInfo {
+                system_id: SystemId(r.u32()?),
+                name: string(r.u32()?)?,
+                constellation_id: ConstellationId(r.u32()?),
+                security_status: r.f64()?,
+                position: Point {
+                    x: r.f64()?,
+                    y: r.f64()?,
+                    z: r.f64()?,
+                },
+                stargates: Vec::new(),
+                gates: Vec::new(),
+                system_index,
+            });
+        }
+        let ends: Vec<usize> = (0..n).map(|_| r.u32()).collect::<Result<_, _>>()?;
+        let mut start = 0;
+        for (i, end) in ends.into_iter().enumerate() {
+            if end < start {
+                return Err(bad("bad stargate offsets"));
+            }
+            for _ in start..end {
+                let destination = r.u32()?;
+                let destination = systems
+                    .get(destination)
+                    .ok_or_else(|| bad("bad stargate destination"))?
+                    .system_id;
+                let position = Point {
+                    x: r.f64()?,
+                    y: r.f64()?,
+                    z: r.f64()?,
+                };
+                let kind = match r.u32()? {
+                    0 => GateKind::Stargate,
+                    1 => GateKind::Bridge,
+                    2 => GateKind::Wormhole,
+                    _ => return Err(bad("bad gate kind")),
+                };
+                let expires = Some(r.u64()?).filter(|&t| t != u64::MAX);
+                let size = match r.u32()? {
+                    0 => None,
+                    1 => Some(WormholeSize::Small),
+                    2 => Some(WormholeSize::Medium),
+                    3 => Some(WormholeSize::Large),
+                    4 => Some(WormholeSize::VeryLarge),
+                    _ => return Err(bad("bad gate size")),
+                };
+                systems[i].gates.push(Stargate {
+                    destination,
+                    position,
+                    kind,
+                    expires,
+                    size,
+                });
+            }
+            systems[i].stargates = systems[i].gates.iter().map(|g| g.destination).collect();
+            start = end;
+        }
+
+        // Read the constellations and regions.
+        let n_constellations = r.u32()?;
+        let mut constellations = Vec::with_capacity(n_constellations);
+        for _ in 0..n_constellations {
+            constellations.push(ConstellationInfo {
+                constellation_id: ConstellationId(r.u32()?),
+                name: string(r.u32()?)?,
+                region_id: RegionId(r.u32()?),
+                systems: r.u32s()?.into_iter().map(SystemId).collect(),
+            });
+        }
+        let n_regions = r.u32()?;
+        let mut regions = Vec::with_capacity(n_regions);
+        for _ in 0..n_regions {
+            regions.push(RegionInfo {
+                region_id: RegionId(r.u32()?),
+                name: string(r.u32()?)?,
+                constellations: r.u32s()?.into_iter().map(ConstellationId).collect(),
+            });
+        }
+        if !r.buf.is_empty() {
+            return Err(bad("trailing data"));
+        }
+        Ok(Map::from_parts(systems, constellations, regions))
+    }
+
+    // Load the compiled map cache at the given path, which
+    // must have been compiled from `source`.
+    pub(crate) fn from_cache_file(path: &Path, source: &[u8]) -> Result<Map, Error> {
+        let file = fs::File::open(path).map_err(Error::Io)?;
+        Map::from_cache(file, Some(source))
+    }
+}
+
+impl Map {
+    /// Hash of the systems of the map and their stargates, in
+    /// system index order. Maps with the same topology hash
+    /// have the same APSP table.
+    pub fn topology_hash(&self) -> u64 {
+        let mut bytes = Vec::new();
+        for system in self.systems() {
+            bytes.extend((system.system_id.0 as u64).to_le_bytes());
+            bytes.extend((system.stargates.len() as u64).to_le_bytes());
+            for destination in &system.stargates {
+                bytes.extend((destination.0 as u64).to_le_bytes());
+            }
+        }
+        fnv1a(&bytes)
+    }
+}
+
+impl APSPTable {
+    /// Save this table, which must have been computed for the
+    /// given map.
+    pub fn write<W: Write>(&self, mut writer: W, map: &Map) -> Result<(), Error> {
+        let mut payload = Vec::with_capacity(2 * self.dist.len());
+        for d in &self.dist {
+            payload.extend(d.to_le_bytes());
+        }
+        let mut header = Vec::with_capacity(36);
+        header.extend(APSP_MAGIC);
+        header.extend(APSP_VERSION.to_le_bytes());
+        header.extend(map.topology_hash().to_le_bytes());
+        header.extend((self.n as u64).to_le_bytes());
+        header.extend(fnv1a(&payload).to_le_bytes());
+        writer.write_all(&header).map_err(Error::Io)?;
+        writer.write_all(&payload).map_err(Error::Io)?;
+        Ok(())
+    }
+
+    /// Load a saved table, checking that it was computed for
+    /// the given map.
+    pub fn read<R: Read>(mut reader: R, map: &Map) -> Result<APSPTable, Error> {
+        let mut bytes = Vec::new();
+        reader.read_to_end(&mut bytes).map_err(Error::Io)?;
+        let mut r = Reader { buf: &bytes };
+        if r.take(APSP_MAGIC.len())? != APSP_MAGIC {
+            return Err(bad("not an APSP table"));
+        }
+        if r.u32()? != APSP_VERSION as usize {
+            return Err(bad("wrong version"));
+        }
+        let n = map.systems().count();
+        if r.u64()? != map.topology_hash() || r.u64()? != n as u64 {
+            return Err(bad("stale"));
+        }
+        let hash = r.u64()?;
+        if r.buf.len() != 2 * n * n || fnv1a(r.buf) != hash {
+            return Err(bad("checksum mismatch"));
+        }
+        let dist = r
+            .buf
+            .chunks_exact(2)
+            .map(|d| u16::from_le_bytes([d[0], d[1]]))
+            .collect();
+        Ok(APSPTable { n, dist })
+    }
+}
+
+/// Load the APSP table saved for the map at the given path.
+pub fn load_apsp<P: AsRef<Path>>(map: &Map, path: P) -> Result<APSPTable, Error> {
+    let file = fs::File::open(path).map_err(Error::Io)?;
+    APSPTable::read(BufReader::new(file), map)
+}
+
+/// Save the given APSP table, which must have been computed
+/// for the map, at the given path. The table takes two bytes
+/// per pair of systems: over 100 MB for New Eden.
+pub fn save_apsp<P: AsRef<Path>>(map: &Map, apsp: &APSPTable, path: P) -> Result<(), Error> {
+    let mut bytes = Vec::new();
+    apsp.write(&mut bytes, map)?;
+    fs::write(path, bytes).map_err(Error::Io)
+}
+
+/// Load the APSP table for the map from the given path if it
+/// was saved there for this map. Otherwise, compute it using
+/// the given number of threads (see `apsp_threads()`). The
+/// table is never saved here: use `save_apsp()` for that.
+pub fn cached_apsp<P: AsRef<Path>>(map: &Map, path: P, threads: usize) -> APSPTable {
+    load_apsp(map, path).unwrap_or_else(|_| apsp_threads(map, threads))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::bridges::*;
+    use crate::testing::*;
+
+    // Check that two maps have the same contents.
+    fn assert_same(a: &Map, b: &Map) {
+        assert_eq!(a.systems().count(), b.systems().count());
+        for (sa, sb) in a.systems().zip(b.systems()) {
+            assert_eq!(sa.system_id, sb.system_id);
+            assert_eq!(sa.system_index, sb.system_index);
+            assert_eq!(sa.name, sb.name);
+            assert_eq!(sa.security_status, sb.security_status);
+            assert_eq!(sa.position, sb.position);
+            assert_eq!(sa.constellation_id, sb.constellation_id);
+            assert_eq!(sa.stargates, sb.stargates);
+            for (ga, gb) in sa.gates.iter().zip(&sb.gates) {
+                assert_eq!(
+                    (ga.position, ga.kind, ga.expires, ga.size),
+                    (gb.position, gb.kind, gb.expires, gb.size)
+                );
+            }
+        }
+        for ca in a.constellations() {
+            let cb = b.constellation(ca.constellation_id).unwrap();
+            assert_eq!((&ca.name, ca.region_id), (&cb.name, cb.region_id));
+            assert_eq!(ca.systems, cb.systems);
+        }
+        for ra in a.regions() {
+            let rb = b.region(ra.region_id).unwrap();
+            assert_eq!(
+                (&ra.name, &ra.constellations),
+                (&rb.name, &rb.constellations)
+            );
+        }
+        assert_eq!(a.regions().count(), b.regions().count());
+    }
+
+    #[test]
+    fn cache_round_trip() {
+        let mut map = tiny_map();
+        let bridge = Bridge {
+            from: "Alpha".to_string(),
+            to: "Gamma".to_string(),
+            alliance: None,
+        };
+        map.add_bridges(&[bridge], None).unwrap();
+        let wormhole = Wormhole {
+            signature: "ABC-123".to_string(),
+            wormhole_type: "K162".to_string(),
+            from: "Alpha".to_string(),
+            to: "Beta".to_string(),
+            size: WormholeSize::Small,
+            expires: 1000,
+        };
+        map.add_wormholes(&[wormhole], 0).unwrap();
+        let mut cache = Vec::new();
+        map.write_cache(&mut cache, TINY_MAP.as_bytes()).unwrap();
+        let cached = Map::from_cache(&cache[..], Some(TINY_MAP.as_bytes())).unwrap();
+        assert_same(&map, &cached);
+        assert_same(&map, &Map::from_reader(&cache[..]).unwrap());
+        let beta = cached.by_name("Beta").unwrap().system_id;
+        assert_eq!("Home", cached.region_of(beta).unwrap().name);
+    }
+
+    #[test]
+    fn full_map_round_trip() {
+        let map = shared_map();
+        let mut cache = Vec::new();
+        map.write_cache(&mut cache, b"").unwrap();
+        assert_same(map, &Map::from_cache(&cache[..], None).unwrap());
+    }
+
+    #[test]
+    fn bad_caches() {
+        let map = tiny_map();
+        let mut cache = Vec::new();
+        map.write_cache(&mut cache, TINY_MAP.as_bytes()).unwrap();
+        let load = |cache: &[u8], source: &[u8]| match Map::from_cache(cache, Some(source)) {
+            Err(Error::BadCache(why)) => why,
+            _ => panic!("loaded a bad cache"),
+        };
+        assert_eq!("stale", load(&cache, b"{}"));
+        let mut corrupt = cache.clone();
+        *corrupt.last_mut().unwrap() ^= 1;
+        assert_eq!("checksum mismatch", load(&corrupt, TINY_MAP.as_bytes()));
+        let mut old = cache.clone();
+        old[8] ^= 0xff;
+        assert_eq!("wrong version", load(&old, TINY_MAP.as_bytes()));
+        assert_eq!("truncated", load(&cache[..20], TINY_MAP.as_bytes()));
+    }
+
+    #[test]
+    fn apsp_round_trip() {
+        let (map, apsp) = map_apsp();
+        let mut saved = Vec::new();
+        apsp.write(&mut saved, map).unwrap();
+        assert!(*apsp == APSPTable::read(&saved[..], map).unwrap());
+
+        // A table for another map is rejected.
+        let tiny = tiny_map();
+        assert!(matches!(
+            APSPTable::read(&saved[..], &tiny),
+            Err(Error::BadCache(why)) if why == "stale"
+        ));
+        let mut corrupt = saved.clone();
+        *corrupt.last_mut().unwrap() ^= 1;
+        assert!(APSPTable::read(&corrupt[..], map).is_err());
+    }
+
+    #[test]
+    fn cached_apsp_file() {
+        let dir = std::env::temp_dir().join(format!("plan-b-apsp-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let path = apsp_path(dir.join("tiny-map.json"));
+        assert_eq!(dir.join("tiny-map.apsp"), path);
+
+        // Without a saved table, the table is computed but
+        // not saved.
+        let map = tiny_map();
+        let apsp = cached_apsp(&map, &path, 1);
+        assert_eq!(Some(2), apsp.dist(0, 2));
+        assert!(!path.exists());
+
+        // Once saved, it is loaded.
+        save_apsp(&map, &apsp, &path).unwrap();
+        let saved = fs::read(&path).unwrap();
+        assert!(apsp == load_apsp(&map, &path).unwrap());
+        assert!(apsp == cached_apsp(&map, &path, 1));
+
+        // A table saved for another map is recomputed, and
+        // left alone.
+        let mut bridged = tiny_map();
+        let bridge = Bridge {
+            from: "Alpha".to_string(),
+            to: "Gamma".to_string(),
+            alliance: None,
+        };
+        bridged.add_bridges(&[bridge], None).unwrap();
+        assert!(load_apsp(&bridged, &path).is_err());
+        assert_eq!(Some(1), cached_apsp(&bridged, &path, 1).dist(0, 2));
+        assert_eq!(saved, fs::read(&path).unwrap());
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn compile_and_fall_back() {
+        let dir = std::env::temp_dir().join(format!("plan-b-cache-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let source = dir.join("tiny-map.json");
+        fs::write(&source, TINY_MAP).unwrap();
+        let cache = Map::compile_cache(&source).unwrap();
+        assert_eq!(dir.join("tiny-map.bin"), cache);
+        let fresh = fs::read(&cache).unwrap();
+        assert!(Map::from_cache_file(&cache, TINY_MAP.as_bytes()).is_ok());
+        assert_same(&tiny_map(), &Map::from_path(&source).unwrap());
+
+        // Changing the map file makes the cache stale, so the
+        // map file is parsed instead.
+        let changed = TINY_MAP.replace("Alpha", "Aleph");
+        fs::write(&source, &changed).unwrap();
+        assert!(Map::from_cache_file(&cache, changed.as_bytes()).is_err());
+        let map = Map::from_path(&source).unwrap();
+        assert!(map.by_name("Aleph").is_some());
+        assert_eq!(fresh, fs::read(&cache).unwrap());
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
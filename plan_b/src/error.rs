@@ -0,0 +1,118 @@
+// Copyright © 2018 Po Huit
+// [This program is licensed under the "MIT License"]
+// Please see the file LICENSE in the source
+// distribution of this software for license terms.
+
+//! Errors for Plan B.
+
+use std::error;
+use std::fmt;
+use std::io;
+use std::path::PathBuf;
+
+use crate::map::*;
+
+/// Things that can go wrong loading the map or routing.
+#[derive(Debug)]
+pub enum Error {
+    /// No map file was found. Lists the paths searched.
+    MapNotFound(Vec<PathBuf>),
+    /// The map data could not be read.
+    Io(io::Error),
+    /// The map file could not be decompressed.
+    Decompress(io::Error),
+    /// The map data does not match the expected JSON schema.
+    Json(serde_json::Error),
+    /// A compiled map cache is stale or damaged, for the
+    /// given reason.
+    BadCache(String),
+    /// A stargate of the given system is missing from the map
+    /// data, or leads to a system that is.
+    DanglingStargate {
+        /// `SystemId` of the system containing the stargate.
+        system: SystemId,
+        /// CCP id of the stargate.
+        stargate: usize,
+    },
+    /// No system has the given name.
+    UnknownSystemName(String),
+    /// No system has the given system id.
+    UnknownSystemId(SystemId),
+    /// A route cost is negative or not finite, as described.
+    BadCost(String),
+    /// A jump drive range is not positive and finite.
+    BadRange(f64),
+    /// There is no route from `start` to `goal`.
+    Unreachable {
+        /// Start of the route.
+        start: SystemId,
+        /// Goal of the route.
+        goal: SystemId,
+        /// True if a route exists, but every route passes
+        /// through an avoided system.
+        avoided: bool,
+    },
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Error::MapNotFound(paths) => {
+                write!(f, "could not find map file (tried")?;
+                for (i, path) in paths.iter().enumerate() {
+                    let sep = if i == 0 { " " } else { ", " };
+                    write!(f, "{}{}", sep, path.display())?;
+                }
+                write!(f, ")")
+            }
+            Error::Io(e) => write!(f, "could not read map data: {}", e),
+            Error::Decompress(e) => write!(f, "could not decompress map data: {}", e),
+            Error::Json(e) => write!(f, "could not parse map data: {}", e),
+            Error::BadCache(why) => write!(f, "unusable map cache: {}", why),
+            Error::DanglingStargate { system, stargate } => {
+                write!(
+                    f,
+                    "stargate {} of system {} leads nowhere",
+                    stargate, system
+                )
+            }
+            Error::UnknownSystemName(name) => write!(f, "system {} not found", name),
+            Error::UnknownSystemId(id) => write!(f, "system id {} not found", id),
+            Error::BadCost(why) => write!(f, "bad route cost: {}", why),
+            Error::BadRange(range) => write!(f, "bad jump range: {} light-years", range),
+            Error::Unreachable {
+                start,
+                goal,
+                avoided,
+            } => {
+                write!(f, "no route found from system {} to system {}", start, goal)?;
+                if *avoided {
+                    write!(f, " avoiding the given systems")?;
+                }
+                Ok(())
+            }
+        }
+    }
+}
+
+impl error::Error for Error {
+    fn source(&self) -> Option<&(dyn error::Error + 'static)> {
+        match self {
+            Error::Io(e) => Some(e),
+            Error::Decompress(e) => Some(e),
+            Error::Json(e) => Some(e),
+            _ => None,
+        }
+    }
+}
+
+impl From<serde_json::Error> for Error {
+    fn from(e: serde_json::Error) -> Self {
+        // Read errors surface through the JSON parser.
+        if e.is_io() {
+            Error::Io(e.into())
+        } else {
+            Error::Json(e)
+        }
+    }
+}
@@ -0,0 +1,591 @@
+// Copyright © 2018 Po Huit
+// [This program is licensed under the "MIT License"]
+// Please see the file LICENSE in the source
+// distribution of this software for license terms.
+
+//! Jump fatigue and cooldown simulation for Plan B.
+
+use std::cmp::Ordering;
+use std::collections::BinaryHeap;
+use std::str::FromStr;
+
+use crate::error::*;
+use crate::jump::*;
+use crate::map::*;
+use crate::search::*;
+
+/// Classes of jump-capable ship, for picking a
+/// `FatigueModel`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum JumpShip {
+    /// Carriers, dreadnoughts, force auxiliaries,
+    /// supercarriers and titans.
+    Capital,
+    /// Black ops battleships.
+    BlackOps,
+    /// Jump freighters.
+    JumpFreighter,
+    /// The Rorqual.
+    Rorqual,
+}
+
+impl FromStr for JumpShip {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "capital" => Ok(JumpShip::Capital),
+            "black-ops" => Ok(JumpShip::BlackOps),
+            "jump-freighter" => Ok(JumpShip::JumpFreighter),
+            "rorqual" => Ok(JumpShip::Rorqual),
+            _ => Err(format!("unknown jump ship {}", s)),
+        }
+    }
+}
+
+/// Parameters of jump fatigue, with times in minutes. A jump
+/// of `d` light-years counts as `d * (1 - reduction)`
+/// effective light-years `e`. The jump starts a reactivation
+/// cooldown of the larger of `1 + e` and a tenth of the
+/// fatigue before the jump, and multiplies that fatigue (at
+/// least `min_fatigue`) by `1 + e`. Fatigue then wears off
+/// minute for minute.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct FatigueModel {
+    /// Fraction by which the ship's role reduces jump
+    /// distances for fatigue.
+    pub reduction: f64,
+    /// Fatigue a jump multiplies when there is less.
+    pub min_fatigue: f64,
+    /// Most fatigue a ship can accumulate.
+    pub max_fatigue: f64,
+    /// Longest reactivation cooldown.
+    pub max_cooldown: f64,
+}
+
+impl FatigueModel {
+    /// Build a model with the given fatigue reduction and the
+    /// usual limits: fatigue of at least ten minutes and at
+    /// most five hours, and cooldowns of at most half an hour.
+    pub fn new(reduction: f64) -> FatigueModel {
+        FatigueModel {
+            reduction,
+            min_fatigue: 10.0,
+            max_fatigue: 300.0,
+            max_cooldown: 30.0,
+        }
+    }
+
+    /// Model for the given class of ship.
+    pub fn for_ship(ship: JumpShip) -> FatigueModel {
+        match ship {
+            JumpShip::Capital => FatigueModel::new(0.0),
+            JumpShip::BlackOps => FatigueModel::new(0.75),
+            JumpShip::JumpFreighter | JumpShip::Rorqual => FatigueModel::new(0.9),
+        }
+    }
+
+    /// Jump `ly` light-years carrying `fatigue`, returning the
+    /// cooldown and the fatigue after the jump.
+    pub fn jump(&self, fatigue: f64, ly: f64) -> (f64, f64) {
+        let factor = 1.0 + ly * (1.0 - self.reduction);
+        let cooldown = factor.max(fatigue / 10.0).min(self.max_cooldown);
+        let fatigue = (fatigue.max(self.min_fatigue) * factor).min(self.max_fatigue);
+        (cooldown, fatigue)
+    }
+
+    /// Simulate flying a jump route, starting with the given
+    /// fatigue and jumping as soon as each cooldown ends.
+    pub fn schedule(&self, route: &JumpRoute, fatigue: f64) -> FatigueSchedule {
+        let mut schedule = FatigueSchedule {
+            departures: Vec::with_capacity(route.distances.len()),
+            cooldowns: Vec::with_capacity(route.distances.len()),
+            fatigue: Vec::with_capacity(route.distances.len()),
+        };
+        let (mut time, mut fatigue) = (0.0, fatigue);
+        for &ly in &route.distances {
+            let (cooldown, after) = self.jump(fatigue, ly);
+            schedule.departures.push(time);
+            schedule.cooldowns.push(cooldown);
+            schedule.fatigue.push(after);
+            time += cooldown;
+            fatigue = (after - cooldown).max(0.0);
+        }
+        schedule
+    }
+}
+
+/// Timing of each jump along a jump route, in minutes.
+#[derive(Clone, Debug, PartialEq)]
+pub struct FatigueSchedule {
+    /// Time of each jump, from the first.
+    pub departures: Vec<f64>,
+    /// Reactivation cooldown after each jump.
+    pub cooldowns: Vec<f64>,
+    /// Fatigue just after each jump.
+    pub fatigue: Vec<f64>,
+}
+
+impl FatigueSchedule {
+    /// Time from the first jump until arrival at the end of
+    /// the route: jumps themselves take no time.
+    pub fn elapsed(&self) -> f64 {
+        self.departures.last().cloned().unwrap_or(0.0)
+    }
+
+    /// Fatigue remaining on arrival at the end of the route.
+    pub fn final_fatigue(&self) -> f64 {
+        self.fatigue.last().cloned().unwrap_or(0.0)
+    }
+}
+
+// A way of arriving at a system: arrival time, the time the
+// next jump is possible, fatigue at that time, and the
+// previous label's index.
+#[derive(Clone, Copy)]
+struct Label {
+    system: usize,
+    arrival: f64,
+    jumps: usize,
+    ready: f64,
+    fatigue: f64,
+    parent: Option<usize>,
+}
+
+impl Label {
+    // True if the route to this label already visits the
+    // system index `i`.
+    fn visits(&self, labels: &[Label], i: usize) -> bool {
+        let mut label = Some(self);
+        while let Some(l) = label {
+            if l.system == i {
+                return true;
+            }
+            label = l.parent.map(|p| &labels[p]);
+        }
+        false
+    }
+
+    // True if jumping on from this label is no worse than
+    // from `other`. Exactly, that takes being ready no later
+    // with no more jumps and the same fatigue, having visited
+    // no system `other` has not: more fatigue can pay off, as
+    // at the fatigue cap it is worked off in a longer cooldown
+    // that leaves less for later jumps. Otherwise, being ready
+    // no later with no more fatigue is taken to be enough.
+    fn dominates(&self, labels: &[Label], other: &Label, exact: bool) -> bool {
+        if self.ready > other.ready || self.fatigue > other.fatigue {
+            return false;
+        }
+        if !exact {
+            return true;
+        }
+        if self.fatigue != other.fatigue || self.jumps > other.jumps {
+            return false;
+        }
+        let mut label = self.parent.map(|p| &labels[p]);
+        while let Some(l) = label {
+            if !other.visits(labels, l.system) {
+                return false;
+            }
+            label = l.parent.map(|p| &labels[p]);
+        }
+        true
+    }
+}
+
+// Priority queue entry, yielding the earliest possible
+// arrival at the goal first, then fewest jumps.
+struct Entry {
+    bound: f64,
+    jumps: usize,
+    label: usize,
+}
+
+impl PartialEq for Entry {
+    fn eq(&self, other: &Self) -> bool {
+        self.cmp(other) == Ordering::Equal
+    }
+}
+
+impl Eq for Entry {}
+
+impl Ord for Entry {
+    fn cmp(&self, other: &Self) -> Ordering {
+        other
+            .bound
+            .total_cmp(&self.bound)
+            .then_with(|| other.jumps.cmp(&self.jumps))
+            .then_with(|| other.label.cmp(&self.label))
+    }
+}
+
+impl PartialOrd for Entry {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+// Most arrivals kept at a system before they are compared
+// inexactly.
+const LABEL_CAP: usize = 16;
+
+// A* search for the loopless jump route arriving soonest,
+// keeping for each system every arrival not dominated by
+// another. The route found is the fastest unless some system
+// collects more than `LABEL_CAP` arrivals, as it may on a
+// large map.
+fn search(
+    map: &Map,
+    start: usize,
+    goal: usize,
+    range: f64,
+    model: &FatigueModel,
+    fatigue: f64,
+    avoid: &Avoid,
+) -> Option<JumpRoute> {
+    let systems = map.systems_ref();
+    let grid = Grid::new(
+        map,
+        range,
+        systems
+            .iter()
+            .filter(|s| jumpable(s) && (s.system_index == goal || !avoid.contains(s.system_id)))
+            .map(|s| s.system_index),
+    );
+    // Each system is jumped from many times over, so find
+    // its jumps just once.
+    let mut in_range = vec![Vec::new(); systems.len()];
+    for s in systems {
+        if jumpable(s) || (s.system_index == start && can_jump_from(s)) {
+            in_range[s.system_index] = grid.in_range(map, s.system_index);
+        }
+    }
+
+    // Fewest jumps from each system to the goal, for bounding
+    // the arrival time: every jump but the last is followed
+    // by a cooldown of at least a minute and a tenth of the
+    // fatigue, which jumps never reduce.
+    let mut remaining = vec![None; systems.len()];
+    let mut frontier = vec![goal];
+    remaining[goal] = Some(0);
+    for jumps in 1.. {
+        let mut next = Vec::new();
+        for &i in &frontier {
+            for &(j, _) in &in_range[i] {
+                if remaining[j].is_none() {
+                    remaining[j] = Some(jumps);
+                    next.push(j);
+                }
+            }
+        }
+        if next.is_empty() {
+            break;
+        }
+        frontier = next;
+    }
+    let bound = |label: &Label| {
+        let k = remaining[label.system]?;
+        if k == 0 {
+            return Some(label.arrival);
+        }
+        let (mut time, mut fatigue) = (label.ready, label.fatigue);
+        for _ in 1..k {
+            let cooldown = (fatigue / 10.0).clamp(1.0, model.max_cooldown);
+            time += cooldown;
+            fatigue = fatigue.max(model.min_fatigue) - cooldown;
+        }
+        Some(time)
+    };
+
+    let mut labels = vec![Label {
+        system: start,
+        arrival: 0.0,
+        jumps: 0,
+        ready: 0.0,
+        fatigue,
+        parent: None,
+    }];
+    let mut kept: Vec<Vec<usize>> = vec![Vec::new(); systems.len()];
+    kept[start].push(0);
+    // Whether each label is still kept.
+    let mut live = vec![true];
+    let mut q = BinaryHeap::new();
+    q.push(Entry {
+        bound: 0.0,
+        jumps: 0,
+        label: 0,
+    });
+    let found = loop {
+        let Entry { label, .. } = q.pop()?;
+        let cur = labels[label];
+        if labels.len() % 100000 == 0 {
+            eprintln!("progress {} {}", labels.len(), q.len());
+        }
+        if cur.system == goal {
+            break label;
+        }
+        if !live[label] {
+            continue;
+        }
+        for &(next, ly) in &in_range[cur.system] {
+            if cur.visits(&labels, next) {
+                continue;
+            }
+            let (cooldown, after) = model.jump(cur.fatigue, ly);
+            let child = Label {
+                system: next,
+                arrival: cur.ready,
+                jumps: cur.jumps + 1,
+                ready: cur.ready + cooldown,
+                fatigue: (after - cooldown).max(0.0),
+                parent: Some(label),
+            };
+            let Some(bound) = bound(&child) else {
+                continue;
+            };
+            // Arrivals at the goal go no further, so need not
+            // be kept.
+            if next != goal {
+                let exact = kept[next].len() < LABEL_CAP;
+                if kept[next]
+                    .iter()
+                    .any(|&k| labels[k].dominates(&labels, &child, exact))
+                {
+                    continue;
+                }
+                kept[next].retain(|&k| {
+                    live[k] = !child.dominates(&labels, &labels[k], exact);
+                    live[k]
+                });
+                kept[next].push(labels.len());
+            }
+            labels.push(child);
+            live.push(true);
+            q.push(Entry {
+                bound,
+                jumps: child.jumps,
+                label: labels.len() - 1,
+            });
+        }
+    };
+
+    eprintln!("labels {}", labels.len());
+    // Walk back from the goal.
+    let mut route = Vec::new();
+    let mut label = Some(found);
+    while let Some(l) = label {
+        route.push(labels[l].system);
+        label = labels[l].parent;
+    }
+    route.reverse();
+    let distances = route
+        .windows(2)
+        .map(|hop| light_years(&systems[hop[0]], &systems[hop[1]]))
+        .collect();
+    Some(JumpRoute {
+        route: route.into_iter().map(|i| systems[i].system_id).collect(),
+        distances,
+    })
+}
+
+/// Return a jump drive route with the given range in
+/// light-years that arrives soonest under the fatigue model,
+/// starting with the given fatigue and jumping as soon as
+/// each cooldown ends, or an error if there is none or the
+/// range is not positive and finite. Among
+/// equally fast routes, fewer jumps win. Like `jump_route()`,
+/// no jump leaves wormhole space or enters high-sec or an
+/// avoided system other than the goal, and no system is
+/// visited twice.
+///
+/// Fatigue wears off during each cooldown, so for ships with
+/// strong fatigue reduction the fastest route may take many
+/// short jumps, each needing a cynosural field.
+pub fn fastest_jump_route(
+    map: &Map,
+    start: SystemId,
+    goal: SystemId,
+    range: f64,
+    model: &FatigueModel,
+    fatigue: f64,
+    avoid: &Avoid,
+) -> Result<JumpRoute, Error> {
+    check_endpoints(map, start, goal)?;
+    check_range(range)?;
+    let i = map.by_system_id(start).system_index;
+    let j = map.by_system_id(goal).system_index;
+    search(map, i, j, range, model, fatigue, avoid).ok_or_else(|| {
+        // The fatigue model has no bearing on whether a route
+        // exists, so ask the plain search why there is none.
+        // Should it disagree, report the goal unreachable.
+        jump_route(map, start, goal, range, avoid)
+            .err()
+            .unwrap_or(Error::Unreachable {
+                start,
+                goal,
+                avoided: false,
+            })
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::*;
+
+    #[test]
+    fn fatigue_timers() {
+        let capital = FatigueModel::for_ship(JumpShip::Capital);
+        // A fresh 5 LY jump: a 6 minute cooldown, and an hour
+        // of fatigue.
+        let (cooldown, fatigue) = capital.jump(0.0, 5.0);
+        assert_eq!((6.0, 60.0), (cooldown, fatigue));
+        // Fatigue and cooldowns are capped.
+        let (cooldown, fatigue) = capital.jump(fatigue, 5.0);
+        assert_eq!((6.0, 300.0), (cooldown, fatigue));
+        let (cooldown, _) = capital.jump(300.0, 1.0);
+        assert_eq!(30.0, cooldown);
+
+        // Jump freighters feel a tenth of the distance.
+        let jf = FatigueModel::for_ship(JumpShip::JumpFreighter);
+        let (cooldown, fatigue) = jf.jump(0.0, 5.0);
+        assert!((cooldown - 1.5).abs() < 1e-9 && (fatigue - 15.0).abs() < 1e-9);
+    }
+
+    #[test]
+    fn schedule_jumps() {
+        let capital = FatigueModel::for_ship(JumpShip::Capital);
+        let route = JumpRoute {
+            route: Vec::new(),
+            distances: vec![5.0, 5.0, 1.0],
+        };
+        let schedule = capital.schedule(&route, 0.0);
+        assert_eq!(vec![0.0, 6.0, 12.0], schedule.departures);
+        assert_eq!(6.0, schedule.cooldowns[1]);
+        // The last jump waits out a tenth of the fatigue left.
+        assert!((schedule.cooldowns[2] - 29.4).abs() < 1e-9);
+        assert_eq!(12.0, schedule.elapsed());
+        assert_eq!(300.0, schedule.final_fatigue());
+    }
+
+    #[test]
+    fn fastest_beats_fewest() {
+        // Optimizing for elapsed time is never slower than
+        // fewest jumps, and takes at least as many jumps.
+        let map = shared_map();
+        let (jita, goal) = (find_system(map, "Jita"), find_system(map, "1DQ1-A"));
+        let none = Avoid::new();
+        for ship in [JumpShip::Capital, JumpShip::JumpFreighter] {
+            let model = FatigueModel::for_ship(ship);
+            let fewest = jump_route(map, jita, goal, 7.0, &none).unwrap();
+            let fastest = fastest_jump_route(map, jita, goal, 7.0, &model, 0.0, &none).unwrap();
+            let slow = model.schedule(&fewest, 0.0).elapsed();
+            let fast = model.schedule(&fastest, 0.0).elapsed();
+            assert!(fast <= slow + 1e-9);
+            assert!(fastest.distances.len() >= fewest.distances.len());
+            assert_eq!(
+                (jita, goal),
+                (fastest.route[0], *fastest.route.last().unwrap())
+            );
+            assert!(fastest.distances.iter().all(|&d| d <= 7.0));
+            let mut visited = fastest.route.clone();
+            visited.sort();
+            visited.dedup();
+            assert_eq!(fastest.route.len(), visited.len());
+        }
+
+        // High-sec cannot be jumped into at all.
+        let model = FatigueModel::for_ship(JumpShip::Capital);
+        let amarr = find_system(map, "Amarr");
+        let e = fastest_jump_route(map, jita, amarr, 7.0, &model, 0.0, &none).unwrap_err();
+        assert!(matches!(e, Error::Unreachable { .. }));
+    }
+
+    #[test]
+    fn fastest_matches_brute_force() {
+        // Eight null-sec systems scattered over a few
+        // light-years, without stargates.
+        let mut seed = 7u64;
+        let mut coord = || {
+            seed = seed
+                .wrapping_mul(6364136223846793005)
+                .wrapping_add(1442695040888963407);
+            (seed >> 33) as f64 / (1u64 << 31) as f64 * 6.0 * LY
+        };
+        let systems: Vec<String> = (1..=8)
+            .map(|id| {
+                format!(
+                    r#""{id}": {{"constellation_id": 10, "name": "S{id}", "security_status": -0.5,
+                        "position": {{"x": {}, "y": {}, "z": 0}}, "system_id": {id},
+                        "stargates": []}}"#,
+                    coord(),
+                    coord(),
+                )
+            })
+            .collect();
+        let json = format!(
+            r#"{{"systems": {{{}}}, "stargates": {{}}}}"#,
+            systems.join(",")
+        );
+        let map = Map::from_reader(json.as_bytes()).unwrap();
+        let (start, goal) = (SystemId(1), SystemId(8));
+        let range = 4.0;
+
+        // Every loopless route, extended a system at a time.
+        let mut routes = Vec::new();
+        let mut partial = vec![vec![start]];
+        while let Some(route) = partial.pop() {
+            let last = map.by_system_id(*route.last().unwrap());
+            for s in map.systems_ref() {
+                if route.contains(&s.system_id) || light_years(last, s) > range {
+                    continue;
+                }
+                let mut next = route.clone();
+                next.push(s.system_id);
+                if s.system_id == goal {
+                    routes.push(next);
+                } else {
+                    partial.push(next);
+                }
+            }
+        }
+        assert!(routes.len() > 1);
+
+        let none = Avoid::new();
+        for ship in [
+            JumpShip::Capital,
+            JumpShip::BlackOps,
+            JumpShip::JumpFreighter,
+        ] {
+            let model = FatigueModel::for_ship(ship);
+            for fatigue in [0.0, 60.0, 290.0] {
+                let timing = |route: &JumpRoute| {
+                    let schedule = model.schedule(route, fatigue);
+                    (schedule.elapsed(), route.distances.len())
+                };
+                let best = routes
+                    .iter()
+                    .map(|route| {
+                        let distances = route
+                            .windows(2)
+                            .map(|hop| {
+                                light_years(map.by_system_id(hop[0]), map.by_system_id(hop[1]))
+                            })
+                            .collect();
+                        timing(&JumpRoute {
+                            route: route.clone(),
+                            distances,
+                        })
+                    })
+                    .min_by(|a, b| a.0.total_cmp(&b.0).then(a.1.cmp(&b.1)))
+                    .unwrap();
+                let fastest =
+                    fastest_jump_route(&map, start, goal, range, &model, fatigue, &none).unwrap();
+                let found = timing(&fastest);
+                assert!((found.0 - best.0).abs() < 1e-9);
+                assert_eq!(best.1, found.1);
+            }
+        }
+    }
+}
@@ -0,0 +1,426 @@
+// Copyright © 2018 Po Huit
+// [This program is licensed under the "MIT License"]
+// Please see the file LICENSE in the source
+// distribution of this software for license terms.
+
+//! Contraction hierarchies for fast weighted route search in
+//! Plan B.
+
+use std::collections::BTreeMap;
+use std::collections::BinaryHeap;
+use std::collections::HashMap;
+
+use crate::map::*;
+use crate::search::*;
+use crate::weighted::*;
+
+// Most nodes a witness search will settle before giving
+// up and adding the shortcut anyway. Extra shortcuts cost
+// query time, never correctness.
+const WITNESS_LIMIT: usize = 500;
+
+/// A contraction hierarchy over the map for a given cost,
+/// answering point-to-point route queries by searching only
+/// "upward" from each end. Based on
+///
+/// > *Contraction Hierarchies: Faster and Simpler Hierarchical  
+/// > Routing in Road Networks*  
+/// > Robert Geisberger, Peter Sanders, Dominik Schultes, Daniel Delling  
+/// > Proc. 7th Workshop on Experimental Algorithms (WEA 2008)  
+///
+/// Systems are contracted one at a time, least important
+/// first, adding shortcut jumps between their neighbors where
+/// needed to preserve route costs. Shortcuts are unpacked, so
+/// queries return full routes.
+///
+/// Where the cost tracks entries, as `TravelModel` does, each
+/// way of entering a system is contracted as a node of its
+/// own, so the hierarchy is several times larger and slower
+/// to build.
+///
+/// The hierarchy knows nothing of avoidance: queries that
+/// avoid systems fall back to a plain search. To route
+/// quickly around a fixed set of systems, build the hierarchy
+/// over a cost that forbids entering them. The hierarchy must
+/// be rebuilt if the map or the cost changes.
+#[derive(Clone, Debug)]
+pub struct ContractionHierarchy<C> {
+    cost: C,
+    // System index of each node, with the index of the
+    // system it was entered from if the cost tracks entries.
+    // Node `i` is system `i` not yet entered, where routes
+    // start.
+    nodes: Vec<(usize, Option<usize>)>,
+    // Nodes of each system index, where routes may end.
+    ends: Vec<Vec<usize>>,
+    // Contraction order of each node.
+    rank: Vec<usize>,
+    // Jumps from each node to higher-ranked nodes, with
+    // their costs.
+    up_out: Vec<Vec<(usize, f64)>>,
+    // Jumps into each node from higher-ranked nodes.
+    up_in: Vec<Vec<(usize, f64)>>,
+    // Cost of every jump and shortcut by its end nodes, with
+    // the node each shortcut bypasses.
+    jumps: HashMap<(usize, usize), (f64, Option<usize>)>,
+}
+
+// Uncontracted part of the graph during contraction: jumps
+// out of and into each node, with their costs.
+struct Remaining {
+    out: Vec<BTreeMap<usize, f64>>,
+    into: Vec<BTreeMap<usize, f64>>,
+}
+
+impl Remaining {
+    // Costs of routes from `start` not passing through
+    // `skip`, found by a Dijkstra search that stops at cost
+    // `max` or after settling `WITNESS_LIMIT` nodes. Every
+    // cost found is that of some real route.
+    fn witness(&self, start: usize, skip: usize, max: f64) -> HashMap<usize, f64> {
+        let mut dist = HashMap::new();
+        let mut q = BinaryHeap::new();
+        let mut settled = 0;
+        dist.insert(start, 0.0);
+        q.push(CostEntry(0.0, start));
+        while let Some(CostEntry(d, i)) = q.pop() {
+            if d > dist[&i] {
+                continue;
+            }
+            settled += 1;
+            if d > max || settled > WITNESS_LIMIT {
+                break;
+            }
+            for (&j, &c) in &self.out[i] {
+                if j != skip && dist.get(&j).is_none_or(|&dj| d + c < dj) {
+                    dist.insert(j, d + c);
+                    q.push(CostEntry(d + c, j));
+                }
+            }
+        }
+        dist
+    }
+
+    // Shortcuts needed to contract node `v`, as their ends
+    // and costs.
+    fn shortcuts(&self, v: usize) -> Vec<(usize, usize, f64)> {
+        let mut shortcuts = Vec::new();
+        for (&u, &cu) in &self.into[v] {
+            let targets: Vec<(usize, f64)> = self.out[v]
+                .iter()
+                .filter(|(&w, _)| w != u)
+                .map(|(&w, &cw)| (w, cu + cw))
+                .collect();
+            let Some(max) = targets.iter().map(|t| t.1).max_by(f64::total_cmp) else {
+                continue;
+            };
+            let dist = self.witness(u, v, max);
+            for (w, c) in targets {
+                if dist.get(&w).is_none_or(|&d| d > c) {
+                    shortcuts.push((u, w, c));
+                }
+            }
+        }
+        shortcuts
+    }
+}
+
+impl<C: Cost> ContractionHierarchy<C> {
+    /// Build the hierarchy for the given cost. Nodes are
+    /// ordered by how many shortcuts contracting them would
+    /// add, less the jumps it would remove, plus how many of
+    /// their neighbors are already contracted.
+    pub fn new(map: &Map, cost: C) -> ContractionHierarchy<C> {
+        let systems = map.systems_ref();
+        let tracks_entry = cost.tracks_entry();
+
+        // One node per system, plus one per way of entering
+        // it if the cost depends on that.
+        let mut nodes: Vec<(usize, Option<usize>)> =
+            (0..systems.len()).map(|i| (i, None)).collect();
+        let mut index: HashMap<(usize, Option<usize>), usize> = nodes
+            .iter()
+            .enumerate()
+            .map(|(k, &node)| (node, k))
+            .collect();
+        if tracks_entry {
+            for j in 0..systems.len() {
+                for &i in map.incoming(j) {
+                    index.entry((j, Some(i))).or_insert_with(|| {
+                        nodes.push((j, Some(i)));
+                        nodes.len() - 1
+                    });
+                }
+            }
+        }
+        let n = nodes.len();
+        let mut ends = vec![Vec::new(); systems.len()];
+        for (k, &(i, _)) in nodes.iter().enumerate() {
+            ends[i].push(k);
+        }
+
+        // Start from the cheapest jump between each pair of
+        // nodes.
+        let mut jumps = HashMap::new();
+        let mut graph = Remaining {
+            out: vec![BTreeMap::new(); n],
+            into: vec![BTreeMap::new(); n],
+        };
+        for (k, &(i, prev)) in nodes.iter().enumerate() {
+            let from = &systems[i];
+            let prev = prev.map(|p| &systems[p]);
+            for (gate, &j) in from.gates.iter().zip(map.outgoing(i)) {
+                let Some(c) = cost.cost(from, gate, &systems[j], prev) else {
+                    continue;
+                };
+                let l = index[&(j, if tracks_entry { Some(i) } else { None })];
+                if k != l && graph.out[k].get(&l).is_none_or(|&old| c < old) {
+                    graph.out[k].insert(l, c);
+                    graph.into[l].insert(k, c);
+                    jumps.insert((k, l), (c, None));
+                }
+            }
+        }
+
+        // Contract nodes in order of priority, updating
+        // priorities lazily as they come up.
+        let mut deleted = vec![0; n];
+        let priority = |graph: &Remaining, deleted: &[usize], v: usize| {
+            let shortcuts = graph.shortcuts(v);
+            let removed = graph.out[v].len() + graph.into[v].len();
+            let p = shortcuts.len() as f64 - removed as f64 + deleted[v] as f64;
+            (p, shortcuts)
+        };
+        let mut q: BinaryHeap<CostEntry> = (0..n)
+            .map(|v| CostEntry(priority(&graph, &deleted, v).0, v))
+            .collect();
+        let mut rank = vec![0; n];
+        let mut up_out = vec![Vec::new(); n];
+        let mut up_in = vec![Vec::new(); n];
+        let mut next_rank = 0;
+        while let Some(CostEntry(_, v)) = q.pop() {
+            let (p, shortcuts) = priority(&graph, &deleted, v);
+            if q.peek().is_some_and(|top| p > top.0) {
+                q.push(CostEntry(p, v));
+                continue;
+            }
+            rank[v] = next_rank;
+            next_rank += 1;
+
+            // Remaining neighbors will be ranked higher.
+            for (w, c) in std::mem::take(&mut graph.out[v]) {
+                graph.into[w].remove(&v);
+                deleted[w] += 1;
+                up_out[v].push((w, c));
+            }
+            for (u, c) in std::mem::take(&mut graph.into[v]) {
+                graph.out[u].remove(&v);
+                deleted[u] += 1;
+                up_in[v].push((u, c));
+            }
+            for (u, w, c) in shortcuts {
+                if graph.out[u].get(&w).is_none_or(|&old| c < old) {
+                    graph.out[u].insert(w, c);
+                    graph.into[w].insert(u, c);
+                    jumps.insert((u, w), (c, Some(v)));
+                }
+            }
+        }
+
+        ContractionHierarchy {
+            cost,
+            nodes,
+            ends,
+            rank,
+            up_out,
+            up_in,
+            jumps,
+        }
+    }
+
+    /// Contraction order of the system with the given index,
+    /// as a route start: 0 for the first node contracted.
+    pub fn rank(&self, i: usize) -> usize {
+        self.rank[i]
+    }
+
+    /// Return a route minimizing the cost, with per-jump
+    /// costs, if one exists. The route will not pass through
+    /// avoided systems; avoiding any system other than the
+    /// endpoints falls back to `weighted_route()`.
+    pub fn route(
+        &self,
+        map: &Map,
+        start: SystemId,
+        goal: SystemId,
+        avoid: &Avoid,
+    ) -> Option<WeightedRoute> {
+        if !avoid.except(&[start, goal]).is_empty() {
+            return weighted_route(map, start, goal, &self.cost, avoid);
+        }
+        let start = map.by_system_id(start).system_index;
+        let goal = map.by_system_id(goal).system_index;
+
+        // Search upward from both ends, alternating by lower
+        // cost, until neither search can improve the best
+        // meeting found.
+        let mut dist = [HashMap::new(), HashMap::new()];
+        let mut parent = [HashMap::new(), HashMap::new()];
+        let mut q = [BinaryHeap::new(), BinaryHeap::new()];
+        let roots = [std::slice::from_ref(&start), &self.ends[goal]];
+        for (side, roots) in roots.into_iter().enumerate() {
+            for &root in roots {
+                dist[side].insert(root, 0.0);
+                q[side].push(CostEntry(0.0, root));
+            }
+        }
+        let mut best = f64::INFINITY;
+        let mut meet = None;
+        loop {
+            let top = |side: usize| q[side].peek().map_or(f64::INFINITY, |e: &CostEntry| e.0);
+            let side = if top(0) <= top(1) { 0 } else { 1 };
+            if top(side) >= best {
+                break;
+            }
+            let CostEntry(d, i) = q[side].pop().unwrap();
+            if d > dist[side][&i] {
+                continue;
+            }
+            if let Some(&other) = dist[1 - side].get(&i) {
+                if d + other < best {
+                    best = d + other;
+                    meet = Some(i);
+                }
+            }
+            let edges = if side == 0 {
+                &self.up_out[i]
+            } else {
+                &self.up_in[i]
+            };
+            for &(j, c) in edges {
+                if dist[side].get(&j).is_none_or(|&dj| d + c < dj) {
+                    dist[side].insert(j, d + c);
+                    parent[side].insert(j, i);
+                    q[side].push(CostEntry(d + c, j));
+                }
+            }
+        }
+        let meet = meet?;
+
+        // Walk back to both ends, then unpack the shortcuts.
+        let mut path = vec![meet];
+        while let Some(&i) = parent[0].get(path.last().unwrap()) {
+            path.push(i);
+        }
+        path.reverse();
+        let mut i = meet;
+        while let Some(&j) = parent[1].get(&i) {
+            path.push(j);
+            i = j;
+        }
+        let mut route = vec![start];
+        let mut costs = Vec::new();
+        for hop in path.windows(2) {
+            self.unpack(hop[0], hop[1], &mut route, &mut costs);
+        }
+        let systems = map.systems_ref();
+        Some(WeightedRoute {
+            route: route
+                .into_iter()
+                .map(|k| systems[self.nodes[k].0].system_id)
+                .collect(),
+            costs,
+        })
+    }
+
+    // Append the nodes after `i` on the jump or shortcut
+    // from `i` to `j`, with their jump costs.
+    fn unpack(&self, i: usize, j: usize, route: &mut Vec<usize>, costs: &mut Vec<f64>) {
+        match self.jumps[&(i, j)] {
+            (c, None) => {
+                route.push(j);
+                costs.push(c);
+            }
+            (_, Some(v)) => {
+                self.unpack(i, v, route, costs);
+                self.unpack(v, j, route, costs);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::*;
+    use crate::travel::*;
+
+    // Check that the hierarchy finds routes as cheap as plain
+    // Dijkstra search between a sample of systems, and that
+    // the routes are real.
+    fn check_matches<C: Cost>(ch: &ContractionHierarchy<C>, avoid: &Avoid) {
+        let map = shared_map();
+        let systems = map.systems_ref();
+        for start in systems.iter().step_by(797) {
+            for goal in systems.iter().step_by(1693) {
+                let (start, goal) = (start.system_id, goal.system_id);
+                let plain = weighted_route(map, start, goal, &ch.cost, avoid);
+                let fast = ch.route(map, start, goal, avoid);
+                let (plain, fast) = match (plain, fast) {
+                    (None, None) => continue,
+                    (Some(plain), Some(fast)) => (plain, fast),
+                    (plain, fast) => panic!("plain {:?}, fast {:?}", plain, fast),
+                };
+                assert!((plain.total_cost() - fast.total_cost()).abs() < 1e-6);
+                assert_eq!((start, goal), (fast.route[0], *fast.route.last().unwrap()));
+                assert_eq!(fast.route.len() - 1, fast.costs.len());
+                for hop in fast.route.windows(2) {
+                    assert!(map.by_system_id(hop[0]).stargates.contains(&hop[1]));
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn hierarchy_matches_dijkstra() {
+        let map = shared_map();
+        let none = Avoid::new();
+        let ch = ContractionHierarchy::new(map, JumpCost);
+        check_matches(&ch, &none);
+        let safer = SecurityCost {
+            preference: Preference::Safer,
+            penalty: DEFAULT_PENALTY,
+        };
+        let ch = ContractionHierarchy::new(map, safer);
+        check_matches(&ch, &none);
+
+        // Avoidance falls back to plain search.
+        let mut avoid = Avoid::new();
+        for name in ["Ahbazon", "Niarja", "Uedama", "Tama"] {
+            avoid.insert(find_system(map, name));
+        }
+        check_matches(&ch, &avoid);
+    }
+
+    #[test]
+    fn hierarchy_tracks_entries() {
+        let model = TravelModel::for_class(ShipClass::Cruiser);
+        let ch = ContractionHierarchy::new(shared_map(), model);
+        check_matches(&ch, &Avoid::new());
+    }
+
+    #[test]
+    fn hierarchy_ranks() {
+        let map = tiny_map();
+        let ch = ContractionHierarchy::new(&map, JumpCost);
+        let mut ranks: Vec<usize> = (0..3).map(|i| ch.rank(i)).collect();
+        ranks.sort();
+        assert_eq!(vec![0, 1, 2], ranks);
+        let (alpha, gamma) = (find_system(&map, "Alpha"), find_system(&map, "Gamma"));
+        let route = ch.route(&map, alpha, gamma, &Avoid::new()).unwrap();
+        assert_eq!(route.route.len() - 1, route.costs.len());
+        assert_eq!(route.costs.len() as f64, route.total_cost());
+        let route = ch.route(&map, alpha, alpha, &Avoid::new()).unwrap();
+        assert_eq!(vec![alpha], route.route);
+    }
+}
@@ -0,0 +1,601 @@
+// Copyright © 2018 Po Huit
+// [This program is licensed under the "MIT License"]
+// Please see the file LICENSE in the source
+// distribution of this software for license terms.
+
+//! Jump drive route planning for Plan B.
+
+use std::cmp::Ordering;
+use std::collections::BinaryHeap;
+use std::collections::HashMap;
+
+use crate::error::*;
+use crate::map::*;
+use crate::search::*;
+use crate::weighted::*;
+
+/// One light-year, in meters.
+pub const LY: f64 = 9_460_730_472_580_800.0;
+
+/// Distance between two systems in light-years.
+pub fn light_years(a: &SystemInfo, b: &SystemInfo) -> f64 {
+    a.position.distance(&b.position) / LY
+}
+
+/// True if a jump drive may jump into the system. High-sec
+/// and wormhole space are closed to jump drives, though ships
+/// may jump out of high-sec.
+pub fn jumpable(system: &SystemInfo) -> bool {
+    system.known_space() && system.security_band() != SecurityBand::High
+}
+
+/// True if a jump drive may be used in the system: anywhere
+/// in known space, but not in wormhole space.
+pub fn can_jump_from(system: &SystemInfo) -> bool {
+    system.known_space()
+}
+
+// Error unless the jump drive range is positive and finite.
+pub(crate) fn check_range(range: f64) -> Result<(), Error> {
+    if range.is_finite() && range > 0.0 {
+        Ok(())
+    } else {
+        Err(Error::BadRange(range))
+    }
+}
+
+/// A route flown by jump drive.
+#[derive(Clone, Debug, PartialEq)]
+pub struct JumpRoute {
+    /// Systems along the route.
+    pub route: Vec<SystemId>,
+    /// Length of each jump along the route, in light-years.
+    pub distances: Vec<f64>,
+}
+
+impl JumpRoute {
+    /// Total length of the route's jumps, in light-years.
+    pub fn total_ly(&self) -> f64 {
+        self.distances.iter().sum()
+    }
+}
+
+// Systems bucketed into cubes of space one jump range on a
+// side, so that every system in range of a given system is
+// in one of the 27 cubes around it.
+pub(crate) struct Grid {
+    side: f64,
+    cells: HashMap<(i64, i64, i64), Vec<usize>>,
+}
+
+impl Grid {
+    // Bucket the given system indices for jumps of up to
+    // `range` light-years.
+    pub(crate) fn new(map: &Map, range: f64, systems: impl Iterator<Item = usize>) -> Grid {
+        let mut grid = Grid {
+            side: range * LY,
+            cells: HashMap::new(),
+        };
+        for i in systems {
+            let cell = grid.cell(&map.systems_ref()[i].position);
+            grid.cells.entry(cell).or_default().push(i);
+        }
+        grid
+    }
+
+    fn cell(&self, p: &Point) -> (i64, i64, i64) {
+        let c = |v: f64| (v / self.side).floor() as i64;
+        (c(p.x), c(p.y), c(p.z))
+    }
+
+    // Indices of the bucketed systems other than `i` within
+    // jump range of system index `i`, with their distances in
+    // light-years, in increasing order of system index.
+    pub(crate) fn in_range(&self, map: &Map, i: usize) -> Vec<(usize, f64)> {
+        let systems = map.systems_ref();
+        let from = &systems[i];
+        let (x, y, z) = self.cell(&from.position);
+        let mut found = Vec::new();
+        for dx in -1..=1 {
+            for dy in -1..=1 {
+                for dz in -1..=1 {
+                    let Some(cell) = self.cells.get(&(x + dx, y + dy, z + dz)) else {
+                        continue;
+                    };
+                    for &j in cell {
+                        let d = from.position.distance(&systems[j].position);
+                        if j != i && d <= self.side {
+                            found.push((j, d / LY));
+                        }
+                    }
+                }
+            }
+        }
+        found.sort_by_key(|&(j, _)| j);
+        found
+    }
+}
+
+// Priority queue entry for the jump search, yielding the
+// fewest jumps first, then the shortest distance.
+struct Entry {
+    jumps: usize,
+    ly: f64,
+    system: usize,
+}
+
+impl PartialEq for Entry {
+    fn eq(&self, other: &Self) -> bool {
+        self.cmp(other) == Ordering::Equal
+    }
+}
+
+impl Eq for Entry {}
+
+impl Ord for Entry {
+    fn cmp(&self, other: &Self) -> Ordering {
+        other
+            .jumps
+            .cmp(&self.jumps)
+            .then_with(|| other.ly.total_cmp(&self.ly))
+            .then_with(|| other.system.cmp(&self.system))
+    }
+}
+
+impl PartialOrd for Entry {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+// Search for a jump route, or `None` if there is none.
+fn search(map: &Map, start: usize, goal: usize, range: f64, avoid: &Avoid) -> Option<JumpRoute> {
+    let systems = map.systems_ref();
+    if start != goal && !can_jump_from(&systems[start]) {
+        return None;
+    }
+    let grid = Grid::new(
+        map,
+        range,
+        systems
+            .iter()
+            .filter(|s| jumpable(s) && (s.system_index == goal || !avoid.contains(s.system_id)))
+            .map(|s| s.system_index),
+    );
+
+    // Best known arrival at each system: jumps, light-years
+    // and parent.
+    let mut best: Vec<Option<(usize, f64, usize)>> = vec![None; systems.len()];
+    let mut q = BinaryHeap::new();
+    best[start] = Some((0, 0.0, start));
+    q.push(Entry {
+        jumps: 0,
+        ly: 0.0,
+        system: start,
+    });
+    while let Some(Entry { jumps, ly, system }) = q.pop() {
+        let (best_jumps, best_ly, _) = best[system].unwrap();
+        if (jumps, ly) != (best_jumps, best_ly) {
+            continue;
+        }
+        if system == goal {
+            break;
+        }
+        for (next, d) in grid.in_range(map, system) {
+            let arrival = (jumps + 1, ly + d);
+            let better = match best[next] {
+                Some((j, l, _)) => arrival.0 < j || (arrival.0 == j && arrival.1 < l),
+                None => true,
+            };
+            if better {
+                best[next] = Some((arrival.0, arrival.1, system));
+                q.push(Entry {
+                    jumps: arrival.0,
+                    ly: arrival.1,
+                    system: next,
+                });
+            }
+        }
+    }
+
+    // Walk back from the goal.
+    best[goal]?;
+    let mut route = vec![goal];
+    let mut distances = Vec::new();
+    let mut i = goal;
+    while i != start {
+        let (_, ly, parent) = best[i].unwrap();
+        distances.push(ly - best[parent].unwrap().1);
+        route.push(parent);
+        i = parent;
+    }
+    route.reverse();
+    distances.reverse();
+    Some(JumpRoute {
+        route: route.into_iter().map(|i| systems[i].system_id).collect(),
+        distances,
+    })
+}
+
+/// Return a route by jump drive with the given range in
+/// light-years, or an error if there is none or the range is
+/// not positive and finite. The route takes the fewest jumps,
+/// then the fewest light-years. No jump leaves wormhole space
+/// or enters high-sec or an avoided system other than the
+/// goal.
+pub fn jump_route(
+    map: &Map,
+    start: SystemId,
+    goal: SystemId,
+    range: f64,
+    avoid: &Avoid,
+) -> Result<JumpRoute, Error> {
+    check_endpoints(map, start, goal)?;
+    check_range(range)?;
+    let i = map.by_system_id(start).system_index;
+    let j = map.by_system_id(goal).system_index;
+    search(map, i, j, range, avoid).ok_or_else(|| {
+        let avoided = !avoid.except(&[start, goal]).is_empty()
+            && search(map, i, j, range, &Avoid::new()).is_some();
+        Error::Unreachable {
+            start,
+            goal,
+            avoided,
+        }
+    })
+}
+
+/// How a hop along a mixed route is made.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Hop {
+    /// Through a gate of the given kind.
+    Gate(GateKind),
+    /// By jump drive.
+    Jump,
+}
+
+/// Costs for routes mixing stargates and jump drive jumps.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct MixedCost {
+    /// Cost of taking a stargate.
+    pub gate: f64,
+    /// Cost of a jump drive jump, whatever its length.
+    pub jump: f64,
+    /// Further cost of a jump drive jump per light-year.
+    pub light_year: f64,
+}
+
+impl Default for MixedCost {
+    /// A jump costs as much as five stargates, whatever its
+    /// length: it needs a cyno, and adds fatigue.
+    fn default() -> Self {
+        MixedCost {
+            gate: 1.0,
+            jump: 5.0,
+            light_year: 0.0,
+        }
+    }
+}
+
+/// A route flown partly through stargates and partly by jump
+/// drive.
+#[derive(Clone, Debug, PartialEq)]
+pub struct MixedRoute {
+    /// Systems along the route.
+    pub route: Vec<SystemId>,
+    /// How each hop along the route is made.
+    pub hops: Vec<Hop>,
+    /// Cost of each hop along the route.
+    pub costs: Vec<f64>,
+}
+
+impl MixedRoute {
+    /// Total cost of the route.
+    pub fn total_cost(&self) -> f64 {
+        self.costs.iter().sum()
+    }
+}
+
+// Search for a cheapest mixed route, or `None` if there is
+// none.
+fn mixed_search(
+    map: &Map,
+    start: usize,
+    goal: usize,
+    range: f64,
+    cost: &MixedCost,
+    avoid: &Avoid,
+) -> Option<MixedRoute> {
+    let systems = map.systems_ref();
+    let allowed = |s: &SystemInfo| s.system_index == goal || !avoid.contains(s.system_id);
+    let grid = Grid::new(
+        map,
+        range,
+        systems
+            .iter()
+            .filter(|s| jumpable(s) && allowed(s))
+            .map(|s| s.system_index),
+    );
+
+    // Best known arrival at each system: cost, parent and
+    // the hop from it.
+    let mut best: Vec<Option<(f64, usize, Hop)>> = vec![None; systems.len()];
+    let mut q = BinaryHeap::new();
+    best[start] = Some((0.0, start, Hop::Gate(GateKind::Stargate)));
+    q.push(CostEntry(0.0, start));
+    while let Some(CostEntry(c, i)) = q.pop() {
+        if c > best[i].unwrap().0 {
+            continue;
+        }
+        if i == goal {
+            break;
+        }
+        // Gates come first, so are preferred over jumps of
+        // equal cost.
+        let gates = map
+            .outgoing(i)
+            .iter()
+            .zip(&systems[i].gates)
+            .filter(|(&j, _)| allowed(&systems[j]))
+            .map(|(&j, gate)| (j, Hop::Gate(gate.kind), cost.gate));
+        let jumps = if can_jump_from(&systems[i]) {
+            grid.in_range(map, i)
+        } else {
+            Vec::new()
+        };
+        let jumps = jumps
+            .into_iter()
+            .map(|(j, ly)| (j, Hop::Jump, cost.jump + cost.light_year * ly));
+        for (j, hop, hop_cost) in gates.chain(jumps) {
+            let arrival = c + hop_cost;
+            if best[j].is_none_or(|(b, _, _)| arrival < b) {
+                best[j] = Some((arrival, i, hop));
+                q.push(CostEntry(arrival, j));
+            }
+        }
+    }
+
+    // Walk back from the goal.
+    best[goal]?;
+    let mut route = vec![goal];
+    let mut hops = Vec::new();
+    let mut costs = Vec::new();
+    let mut i = goal;
+    while i != start {
+        let (c, parent, hop) = best[i].unwrap();
+        costs.push(c - best[parent].unwrap().0);
+        hops.push(hop);
+        route.push(parent);
+        i = parent;
+    }
+    route.reverse();
+    hops.reverse();
+    costs.reverse();
+    Some(MixedRoute {
+        route: route.into_iter().map(|i| systems[i].system_id).collect(),
+        hops,
+        costs,
+    })
+}
+
+/// Return a cheapest route mixing gates and jumps by jump
+/// drive with the given range in light-years, or an error if
+/// there is none, the range is not positive and finite, or a
+/// cost is negative or not finite. No hop enters an avoided
+/// system other than the goal, and no jump leaves wormhole
+/// space or enters high-sec.
+pub fn mixed_route(
+    map: &Map,
+    start: SystemId,
+    goal: SystemId,
+    range: f64,
+    cost: &MixedCost,
+    avoid: &Avoid,
+) -> Result<MixedRoute, Error> {
+    check_endpoints(map, start, goal)?;
+    check_range(range)?;
+    let costs = [
+        ("gate", cost.gate),
+        ("jump", cost.jump),
+        ("light-year", cost.light_year),
+    ];
+    for (name, c) in costs {
+        if !(c.is_finite() && c >= 0.0) {
+            return Err(Error::BadCost(format!("{} cost {}", name, c)));
+        }
+    }
+    let i = map.by_system_id(start).system_index;
+    let j = map.by_system_id(goal).system_index;
+    mixed_search(map, i, j, range, cost, avoid).ok_or_else(|| {
+        let avoided = !avoid.except(&[start, goal]).is_empty()
+            && mixed_search(map, i, j, range, cost, &Avoid::new()).is_some();
+        Error::Unreachable {
+            start,
+            goal,
+            avoided,
+        }
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::bridges::*;
+    use crate::testing::*;
+
+    #[test]
+    fn tiny_jumps() {
+        // Alpha, Beta and Gamma lie a meter apart in a line.
+        let map = tiny_map();
+        let id = |name| find_system(&map, name);
+        let none = Avoid::new();
+        let direct = jump_route(&map, id("Alpha"), id("Gamma"), 2.0 / LY, &none).unwrap();
+        assert_eq!(vec![id("Alpha"), id("Gamma")], direct.route);
+        let short = jump_route(&map, id("Alpha"), id("Gamma"), 1.5 / LY, &none).unwrap();
+        assert_eq!(vec![id("Alpha"), id("Beta"), id("Gamma")], short.route);
+        assert!((short.total_ly() * LY - 2.0).abs() < 1e-6);
+
+        // Alpha is high-sec, so cannot be jumped into.
+        let e = jump_route(&map, id("Gamma"), id("Alpha"), 2.0 / LY, &none).unwrap_err();
+        assert!(matches!(e, Error::Unreachable { avoided: false, .. }));
+        let mut avoid = Avoid::new();
+        avoid.insert(id("Beta"));
+        let e = jump_route(&map, id("Alpha"), id("Gamma"), 1.5 / LY, &avoid).unwrap_err();
+        assert!(matches!(e, Error::Unreachable { avoided: true, .. }));
+    }
+
+    #[test]
+    fn jump_origin_and_range() {
+        // A wormhole system and a null-sec system a
+        // light-year apart, without stargates.
+        let json = format!(
+            r#"{{"systems": {{
+                "31000001": {{"constellation_id": 21000001, "name": "J100001",
+                    "security_status": -1.0, "position": {{"x": 0, "y": 0, "z": 0}},
+                    "system_id": 31000001, "stargates": []}},
+                "30000001": {{"constellation_id": 20000001, "name": "Null",
+                    "security_status": -0.5, "position": {{"x": {}, "y": 0, "z": 0}},
+                    "system_id": 30000001, "stargates": []}}
+            }}, "stargates": {{}}}}"#,
+            LY
+        );
+        let map = Map::from_reader(json.as_bytes()).unwrap();
+        let (wormhole, null) = (SystemId(31000001), SystemId(30000001));
+        let none = Avoid::new();
+        let e = jump_route(&map, wormhole, null, 2.0, &none).unwrap_err();
+        assert!(matches!(e, Error::Unreachable { avoided: false, .. }));
+        // Wormhole space cannot be jumped into either.
+        let e = jump_route(&map, null, wormhole, 2.0, &none).unwrap_err();
+        assert!(matches!(e, Error::Unreachable { avoided: false, .. }));
+
+        for range in [0.0, -1.0, f64::NAN, f64::INFINITY] {
+            let e = jump_route(&map, null, wormhole, range, &none).unwrap_err();
+            assert!(matches!(e, Error::BadRange(_)));
+        }
+    }
+
+    #[test]
+    fn jump_freighter_route() {
+        // Check a jump freighter route from Jita against a
+        // brute-force search for the fewest jumps.
+        let map = shared_map();
+        let systems = map.systems_ref();
+        let (jita, goal) = (find_system(map, "Jita"), find_system(map, "1DQ1-A"));
+        let range = 10.0;
+        let jumps = jump_route(map, jita, goal, range, &Avoid::new()).unwrap();
+        assert_eq!(jumps.route.len() - 1, jumps.distances.len());
+        for (hop, &d) in jumps.route.windows(2).zip(&jumps.distances) {
+            let (a, b) = (map.by_system_id(hop[0]), map.by_system_id(hop[1]));
+            assert!(jumpable(b));
+            assert!((light_years(a, b) - d).abs() < 1e-9);
+            assert!(d <= range);
+        }
+
+        let mut dist = vec![None; systems.len()];
+        let mut frontier = vec![map.by_system_id(jita).system_index];
+        dist[frontier[0]] = Some(0);
+        let mut level = 0;
+        while !frontier.is_empty() {
+            level += 1;
+            let mut next = Vec::new();
+            for &i in &frontier {
+                for (j, s) in systems.iter().enumerate() {
+                    if dist[j].is_none() && jumpable(s) && light_years(&systems[i], s) <= range {
+                        dist[j] = Some(level);
+                        next.push(j);
+                    }
+                }
+            }
+            frontier = next;
+        }
+        let goal = map.by_system_id(goal).system_index;
+        assert_eq!(dist[goal], Some(jumps.distances.len()));
+    }
+
+    #[test]
+    fn tiny_mixed() {
+        // Alpha, Beta and Gamma lie a meter apart in a line,
+        // joined by stargates.
+        let map = tiny_map();
+        let id = |name| find_system(&map, name);
+        let none = Avoid::new();
+        let range = 1.5 / LY;
+        let gates = mixed_route(
+            &map,
+            id("Alpha"),
+            id("Gamma"),
+            range,
+            &MixedCost::default(),
+            &none,
+        )
+        .unwrap();
+        let stargate = Hop::Gate(GateKind::Stargate);
+        assert_eq!(vec![stargate, stargate], gates.hops);
+        assert_eq!(2.0, gates.total_cost());
+
+        // Cheap jumps win, but cannot enter high-sec Alpha.
+        let cheap = MixedCost {
+            jump: 0.5,
+            ..MixedCost::default()
+        };
+        let there = mixed_route(&map, id("Alpha"), id("Gamma"), range, &cheap, &none).unwrap();
+        assert_eq!(vec![Hop::Jump, Hop::Jump], there.hops);
+        let back = mixed_route(&map, id("Gamma"), id("Alpha"), range, &cheap, &none).unwrap();
+        assert_eq!(vec![id("Gamma"), id("Beta"), id("Alpha")], back.route);
+        assert_eq!(vec![Hop::Jump, stargate], back.hops);
+        assert_eq!(1.5, back.total_cost());
+
+        let mut avoid = Avoid::new();
+        avoid.insert(id("Beta"));
+        let e = mixed_route(&map, id("Alpha"), id("Gamma"), range, &cheap, &avoid).unwrap_err();
+        assert!(matches!(e, Error::Unreachable { avoided: true, .. }));
+
+        // Negative costs would make the search run forever.
+        for bad in [-1.0, f64::NAN, f64::INFINITY] {
+            let cost = MixedCost {
+                jump: bad,
+                ..MixedCost::default()
+            };
+            let e = mixed_route(&map, id("Alpha"), id("Gamma"), range, &cost, &none).unwrap_err();
+            assert!(matches!(e, Error::BadCost(_)));
+        }
+        for range in [0.0, -1.0, f64::NAN, f64::INFINITY] {
+            let e = mixed_route(&map, id("Alpha"), id("Gamma"), range, &cheap, &none).unwrap_err();
+            assert!(matches!(e, Error::BadRange(_)));
+        }
+
+        // Hops through bridges say so.
+        let mut bridged = map.clone();
+        let bridge = Bridge {
+            from: "Gamma".to_string(),
+            to: "Alpha".to_string(),
+            alliance: None,
+        };
+        bridged.add_bridges(&[bridge], None).unwrap();
+        let cost = MixedCost::default();
+        let home = mixed_route(&bridged, id("Gamma"), id("Alpha"), range, &cost, &none).unwrap();
+        assert_eq!(vec![Hop::Gate(GateKind::Bridge)], home.hops);
+    }
+
+    #[test]
+    fn mixed_jita_route() {
+        // A mixed route is no dearer than gating all the way
+        // or jumping all the way, and each hop is possible.
+        let map = shared_map();
+        let (jita, goal) = (find_system(map, "Jita"), find_system(map, "1DQ1-A"));
+        let (range, cost) = (7.0, MixedCost::default());
+        let none = Avoid::new();
+        let mixed = mixed_route(map, jita, goal, range, &cost, &none).unwrap();
+        let gates = shortest_route(map, jita, goal, &none).unwrap();
+        let jumps = jump_route(map, jita, goal, range, &none).unwrap();
+        assert!(mixed.total_cost() <= (gates.len() - 1) as f64 * cost.gate);
+        assert!(mixed.total_cost() <= jumps.distances.len() as f64 * cost.jump);
+        for (hop, kind) in mixed.route.windows(2).zip(&mixed.hops) {
+            let (a, b) = (map.by_system_id(hop[0]), map.by_system_id(hop[1]));
+            match kind {
+                Hop::Gate(kind) => assert_eq!(Some(*kind), a.gate_to(b.system_id).map(|g| g.kind)),
+                Hop::Jump => assert!(jumpable(b) && light_years(a, b) <= range),
+            }
+        }
+    }
+}
@@ -0,0 +1,230 @@
+// Copyright © 2018 Po Huit
+// [This program is licensed under the "MIT License"]
+// Please see the file LICENSE in the source
+// distribution of this software for license terms.
+
+//! Landmark (ALT) lower bounds for fast A* route search in
+//! Plan B.
+
+use std::collections::BinaryHeap;
+
+use crate::map::*;
+use crate::weighted::*;
+
+/// A cost together with precomputed costs to and from a few
+/// landmark systems. Its estimate bounds the cost to a goal
+/// from below by the triangle inequality, so using it in
+/// place of the underlying cost makes `weighted_route()` an
+/// A* search that finds routes exactly as cheap, expanding
+/// far fewer systems. Based on
+///
+/// > *Computing the Shortest Path: A\* Search Meets Graph Theory*  
+/// > Andrew V. Goldberg, Chris Harrelson  
+/// > Proc. 16th ACM-SIAM Symposium on Discrete Algorithms (SODA 2005)  
+///
+/// The bounds stay valid when avoiding systems, since that
+/// can only make routes dearer. They must be rebuilt if the
+/// map or the cost changes.
+#[derive(Clone, Debug)]
+pub struct Landmarks<C> {
+    cost: C,
+    // System indices of the landmarks.
+    landmarks: Vec<usize>,
+    // Cost from each landmark to each system, by landmark
+    // then system index.
+    from: Vec<Vec<f64>>,
+    // Cost to each landmark from each system.
+    to: Vec<Vec<f64>>,
+}
+
+// Cheapest cost from `root` to every system index over the
+// given jump costs, infinite if unreachable.
+fn costs_from(jumps: &[Vec<(usize, f64)>], root: usize) -> Vec<f64> {
+    let mut dist = vec![f64::INFINITY; jumps.len()];
+    let mut q = BinaryHeap::new();
+    dist[root] = 0.0;
+    q.push(CostEntry(0.0, root));
+    while let Some(CostEntry(d, i)) = q.pop() {
+        if d > dist[i] {
+            continue;
+        }
+        for &(j, c) in &jumps[i] {
+            if d + c < dist[j] {
+                dist[j] = d + c;
+                q.push(CostEntry(d + c, j));
+            }
+        }
+    }
+    dist
+}
+
+impl<C: Cost> Landmarks<C> {
+    /// Choose `count` landmark systems for the given cost and
+    /// compute costs to and from them. Each landmark is the
+    /// system with stargates farthest from those already
+    /// chosen, starting from the first such system, so systems
+    /// unreachable from the landmarks so far are preferred.
+    pub fn new(map: &Map, cost: C, count: usize) -> Landmarks<C> {
+        let n = map.systems_ref().len();
+        let forward = jump_costs(map, &cost);
+        let mut backward = vec![Vec::new(); n];
+        for (i, jumps) in forward.iter().enumerate() {
+            for &(j, c) in jumps {
+                backward[j].push((i, c));
+            }
+        }
+
+        let mut landmarks = Vec::new();
+        let mut from: Vec<Vec<f64>> = Vec::new();
+        let mut to = Vec::new();
+        // Systems without stargates would make useless
+        // landmarks.
+        let candidates: Vec<usize> = (0..n).filter(|&i| !forward[i].is_empty()).collect();
+        let mut nearest = vec![f64::INFINITY; n];
+        let mut next = candidates.first().cloned().unwrap_or(0);
+        while landmarks.len() < count.min(candidates.len()) {
+            landmarks.push(next);
+            from.push(costs_from(&forward, next));
+            to.push(costs_from(&backward, next));
+
+            // Pick the next landmark farthest from these.
+            for (d, &c) in nearest.iter_mut().zip(from.last().unwrap()) {
+                *d = d.min(c);
+            }
+            for &l in &landmarks {
+                nearest[l] = f64::NEG_INFINITY;
+            }
+            next = candidates
+                .iter()
+                .cloned()
+                .max_by(|&a, &b| nearest[a].total_cmp(&nearest[b]).then(b.cmp(&a)))
+                .unwrap_or(0);
+        }
+        Landmarks {
+            cost,
+            landmarks,
+            from,
+            to,
+        }
+    }
+
+    /// System ids of the landmark systems.
+    pub fn landmarks<'a>(&'a self, map: &'a Map) -> impl Iterator<Item = SystemId> + 'a {
+        let systems = map.systems_ref();
+        self.landmarks.iter().map(|&l| systems[l].system_id)
+    }
+
+    /// Lower bound on the cost from system index `i` to
+    /// system index `j`.
+    pub fn bound(&self, i: usize, j: usize) -> f64 {
+        let mut bound: f64 = 0.0;
+        for (from, to) in self.from.iter().zip(&self.to) {
+            // Costs involving unreachable systems bound
+            // nothing.
+            if from[i].is_finite() && from[j].is_finite() {
+                bound = bound.max(from[j] - from[i]);
+            }
+            if to[i].is_finite() && to[j].is_finite() {
+                bound = bound.max(to[i] - to[j]);
+            }
+        }
+        bound
+    }
+}
+
+impl<C: Cost> Cost for Landmarks<C> {
+    fn cost(
+        &self,
+        from: &SystemInfo,
+        gate: &Stargate,
+        to: &SystemInfo,
+        prev: Option<&SystemInfo>,
+    ) -> Option<f64> {
+        self.cost.cost(from, gate, to, prev)
+    }
+
+    fn tracks_entry(&self) -> bool {
+        self.cost.tracks_entry()
+    }
+
+    fn estimate(&self, from: &SystemInfo, goal: &SystemInfo) -> f64 {
+        let bound = self.bound(from.system_index, goal.system_index);
+        bound.max(self.cost.estimate(from, goal))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::search::*;
+    use crate::testing::*;
+    use crate::travel::*;
+
+    // Check that A* with landmarks finds routes as cheap as
+    // plain Dijkstra search between a sample of systems.
+    fn check_matches<C: Cost>(cost: C, avoid: &Avoid) {
+        let map = shared_map();
+        let landmarks = Landmarks::new(map, cost, 8);
+        let systems = map.systems_ref();
+        for start in systems.iter().step_by(1201) {
+            for goal in systems.iter().step_by(1693) {
+                let (start, goal) = (start.system_id, goal.system_id);
+                let plain = weighted_route(map, start, goal, &landmarks.cost, avoid);
+                let alt = weighted_route(map, start, goal, &landmarks, avoid);
+                match (plain, alt) {
+                    (None, None) => (),
+                    (Some(plain), Some(alt)) => {
+                        assert!((plain.total_cost() - alt.total_cost()).abs() < 1e-6);
+                        assert_eq!(alt.route.len() - 1, alt.costs.len());
+                    }
+                    (plain, alt) => panic!("plain {:?}, alt {:?}", plain, alt),
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn landmarks_match_dijkstra() {
+        let none = Avoid::new();
+        check_matches(JumpCost, &none);
+        let safer = SecurityCost {
+            preference: Preference::Safer,
+            penalty: DEFAULT_PENALTY,
+        };
+        check_matches(safer, &none);
+        let model = TravelModel::for_class(ShipClass::Cruiser);
+        check_matches(model, &none);
+
+        let map = shared_map();
+        let mut avoid = Avoid::new();
+        for name in ["Ahbazon", "Niarja", "Uedama", "Tama"] {
+            avoid.insert(find_system(map, name));
+        }
+        check_matches(safer, &avoid);
+    }
+
+    #[test]
+    fn landmark_bounds() {
+        // The bounds are below true jump distances, and exact
+        // for routes from or to a landmark.
+        let (map, apsp) = map_apsp();
+        let landmarks = Landmarks::new(map, JumpCost, 4);
+        let chosen: Vec<usize> = landmarks
+            .landmarks(map)
+            .map(|s| map.by_system_id(s).system_index)
+            .collect();
+        assert_eq!(4, chosen.len());
+        for i in (0..apsp.len()).step_by(301) {
+            for j in (0..apsp.len()).step_by(89) {
+                if let Some(d) = apsp.dist(i, j) {
+                    assert!(landmarks.bound(i, j) <= d as f64);
+                }
+            }
+            for &l in &chosen {
+                if let Some(d) = apsp.dist(i, l) {
+                    assert_eq!(d as f64, landmarks.bound(i, l));
+                }
+            }
+        }
+    }
+}
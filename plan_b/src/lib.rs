@@ -8,8 +8,33 @@
 //! This crate provides facilities for routing in the New
 //! Eden universe.
 
+pub mod bridges;
+pub mod cache;
+pub mod error;
+pub mod fatigue;
+pub mod hierarchy;
+pub mod jump;
+pub mod landmarks;
 pub mod map;
+pub mod plan;
 pub mod search;
+pub mod travel;
+pub mod weighted;
+pub mod wormholes;
 
+#[cfg(test)]
+mod testing;
+
+pub use crate::bridges::*;
+pub use crate::cache::*;
+pub use crate::error::*;
+pub use crate::fatigue::*;
+pub use crate::hierarchy::*;
+pub use crate::jump::*;
+pub use crate::landmarks::*;
 pub use crate::map::*;
+pub use crate::plan::*;
 pub use crate::search::*;
+pub use crate::travel::*;
+pub use crate::weighted::*;
+pub use crate::wormholes::*;
@@ -6,39 +6,229 @@
 //! Map data management for Plan B.
 
 use std::collections::HashMap;
-use std::error::Error;
-use std::fs::File;
+use std::env;
+use std::fmt;
+use std::fs;
+use std::io::{self, BufRead, BufReader, Read};
+use std::path::{Path, PathBuf};
 use std::slice;
 
 use libflate::gzip;
 
+use crate::cache::*;
+use crate::error::*;
+use crate::wormholes::*;
+
 /// A `SystemId` as defined by CCP.
 #[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord, Hash)]
-pub struct SystemId(usize);
+pub struct SystemId(pub(crate) usize);
+
+impl fmt::Display for SystemId {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.0)
+    }
+}
+
+/// A `ConstellationId` as defined by CCP.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct ConstellationId(pub(crate) usize);
+
+/// A `RegionId` as defined by CCP.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct RegionId(pub(crate) usize);
+
+/// A position in space, in meters.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Point {
+    /// X coordinate.
+    pub x: f64,
+    /// Y coordinate.
+    pub y: f64,
+    /// Z coordinate.
+    pub z: f64,
+}
+
+impl Point {
+    /// Straight-line distance to another point, in meters.
+    pub fn distance(&self, other: &Point) -> f64 {
+        let dx = self.x - other.x;
+        let dy = self.y - other.y;
+        let dz = self.z - other.z;
+        (dx * dx + dy * dy + dz * dz).sqrt()
+    }
+}
+
+/// Kinds of gate between systems, in order of preference
+/// where several lead to the same place.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub enum GateKind {
+    /// A stargate, from the map data.
+    Stargate,
+    /// A player-built jump bridge, from an overlay.
+    Bridge,
+    /// A wormhole connection, from an overlay.
+    Wormhole,
+}
+
+/// An outgoing stargate of a system.
+#[derive(Clone, Copy, Debug)]
+pub struct Stargate {
+    /// `SystemId` of the system this gate leads to.
+    pub destination: SystemId,
+    /// Position of this gate within its system.
+    pub position: Point,
+    /// Kind of this gate.
+    pub kind: GateKind,
+    /// When this gate stops working, in seconds since the Unix
+    /// epoch, if ever.
+    pub expires: Option<u64>,
+    /// Largest class of ship this gate passes, if limited.
+    pub size: Option<WormholeSize>,
+}
 
 /// Map info on a given system.
-#[derive(Debug)]
+#[derive(Clone, Debug)]
 pub struct SystemInfo {
     /// `SystemId` of this system.
     pub system_id: SystemId,
     /// Name of this system.
     pub name: String,
+    /// Security status of this system, as reported by CCP.
+    pub security_status: f64,
+    /// Position of this system in New Eden, in meters.
+    pub position: Point,
+    /// `ConstellationId` of the constellation containing this
+    /// system.
+    pub constellation_id: ConstellationId,
     /// `SystemId`s of systems connected to this one
     /// via outgoing stargates.
     pub stargates: Vec<SystemId>,
+    /// Outgoing stargates, in the same order as `stargates`.
+    pub gates: Vec<Stargate>,
     /// Index into the `Map`'s internal system list.
     pub system_index: usize,
 }
 
+/// Map info on a given constellation.
+#[derive(Clone, Debug)]
+pub struct ConstellationInfo {
+    /// `ConstellationId` of this constellation.
+    pub constellation_id: ConstellationId,
+    /// Name of this constellation.
+    pub name: String,
+    /// `RegionId` of the region containing this
+    /// constellation.
+    pub region_id: RegionId,
+    /// `SystemId`s of the systems in this constellation.
+    pub systems: Vec<SystemId>,
+}
+
+/// Map info on a given region.
+#[derive(Clone, Debug)]
+pub struct RegionInfo {
+    /// `RegionId` of this region.
+    pub region_id: RegionId,
+    /// Name of this region.
+    pub name: String,
+    /// `ConstellationId`s of the constellations in this
+    /// region.
+    pub constellations: Vec<ConstellationId>,
+}
+
+/// Security band of a system, as shown in the EVE client.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum SecurityBand {
+    /// High-sec: security 0.5 and above.
+    High,
+    /// Low-sec: security 0.1 through 0.4.
+    Low,
+    /// Null-sec: security 0.0 and below.
+    Null,
+}
+
+impl SystemInfo {
+    /// Security band of this system. The client rounds
+    /// security status to one decimal place, except that any
+    /// positive status rounds up to at least 0.1.
+    pub fn security_band(&self) -> SecurityBand {
+        if self.security_status >= 0.45 {
+            SecurityBand::High
+        } else if self.security_status > 0.0 {
+            SecurityBand::Low
+        } else {
+            SecurityBand::Null
+        }
+    }
+
+    /// True if this system is in known space. Wormhole space
+    /// (including Thera) and the other systems without
+    /// stargates beyond it have system ids from 31000000 up.
+    pub fn known_space(&self) -> bool {
+        self.system_id.0 < 31_000_000
+    }
+
+    /// Return the stargate in this system leading to the
+    /// given system, if any.
+    pub fn gate_to(&self, destination: SystemId) -> Option<&Stargate> {
+        self.gates.iter().find(|g| g.destination == destination)
+    }
+}
+
 /// The map, containing info needed for routing.
-#[derive(Debug)]
+#[derive(Clone, Debug)]
 pub struct Map {
     systems: Vec<SystemInfo>,
     by_system_id: HashMap<SystemId, usize>,
     by_name: HashMap<String, usize>,
+    constellations: HashMap<ConstellationId, ConstellationInfo>,
+    regions: HashMap<RegionId, RegionInfo>,
+    regions_by_name: HashMap<String, RegionId>,
+    // Stargate destinations and sources of each system, by
+    // system index.
+    outgoing: Vec<Vec<usize>>,
+    incoming: Vec<Vec<usize>>,
+}
+
+// A two-way link between system indices, to be added to the
+// map as a gate each way.
+#[derive(Clone, Copy, Debug)]
+pub(crate) struct Link {
+    pub(crate) from: usize,
+    pub(crate) to: usize,
+    pub(crate) expires: Option<u64>,
+    pub(crate) size: Option<WormholeSize>,
 }
 
-// JSON representations of map data as Rust structs.
+// Region ids by region name.
+fn region_names(regions: &HashMap<RegionId, RegionInfo>) -> HashMap<String, RegionId> {
+    regions
+        .values()
+        .map(|r| (r.name.clone(), r.region_id))
+        .collect()
+}
+
+// Outgoing and incoming stargate adjacency by system index.
+// Outgoing lists are in stargate order.
+fn adjacency(
+    systems: &[SystemInfo],
+    by_system_id: &HashMap<SystemId, usize>,
+) -> (Vec<Vec<usize>>, Vec<Vec<usize>>) {
+    let outgoing: Vec<Vec<usize>> = systems
+        .iter()
+        .map(|s| s.stargates.iter().map(|d| by_system_id[d]).collect())
+        .collect();
+    let mut incoming = vec![Vec::new(); systems.len()];
+    for (i, dests) in outgoing.iter().enumerate() {
+        for &j in dests {
+            incoming[j].push(i);
+        }
+    }
+    (outgoing, incoming)
+}
+
+// JSON representations of map data as Rust structs. Not
+// every field is used yet.
+#[allow(dead_code)]
 mod json_repr {
     use std::collections::HashMap;
 
@@ -121,95 +311,316 @@ mod json_repr {
         pub system_id: usize,
     }
 
+    #[derive(Deserialize)]
+    pub struct Constellation {
+        pub constellation_id: usize,
+        pub name: String,
+        pub region_id: usize,
+        pub systems: Vec<usize>,
+    }
+
+    #[derive(Deserialize)]
+    pub struct Region {
+        pub constellations: Vec<usize>,
+        pub name: String,
+        pub region_id: usize,
+    }
+
+    // Constellations and regions are missing from older map
+    // data.
     #[derive(Deserialize)]
     pub struct Map {
         pub stargates: HashMap<usize, Stargate>,
         pub systems: HashMap<usize, System>,
+        #[serde(default)]
+        pub constellations: HashMap<usize, Constellation>,
+        #[serde(default)]
+        pub regions: HashMap<usize, Region>,
     }
 }
 
-fn find_map_file() -> Result<File, Box<dyn Error>> {
-    let mut f = None;
-    for fname in [
-        "../eve-map.json.gz",
-        "./static/eve-map.json.gz",
-        "./eve-map.json.gz",
-        "/usr/local/share/eve-map.json.gz",
-    ]
-    .iter()
-    {
-        f = Some(File::open(fname));
-        if let Some(Ok(f)) = f {
-            return Ok(f);
+/// Environment variable giving the path of the map file. When
+/// set, `map_path()` gives only this path.
+pub const MAP_ENV: &str = "PLAN_B_MAP";
+
+/// Paths searched in order by `map_path()` for the map file
+/// when `MAP_ENV` is not set.
+pub const MAP_PATHS: [&str; 4] = [
+    "../eve-map.json.gz",
+    "./static/eve-map.json.gz",
+    "./eve-map.json.gz",
+    "/usr/local/share/eve-map.json.gz",
+];
+
+// Read the map file at the given path. A missing file is
+// reported as not found at that path.
+pub(crate) fn read_map_file(path: &Path) -> Result<Vec<u8>, Error> {
+    fs::read(path).map_err(|e| match e.kind() {
+        io::ErrorKind::NotFound => Error::MapNotFound(vec![path.to_path_buf()]),
+        _ => Error::Io(e),
+    })
+}
+
+/// Path of the map file: the file named by the `MAP_ENV`
+/// environment variable if set, or else the first of
+/// `MAP_PATHS` found. A candidate that exists but cannot be
+/// examined is reported rather than skipped.
+pub fn map_path() -> Result<PathBuf, Error> {
+    if let Some(path) = env::var_os(MAP_ENV) {
+        return Ok(PathBuf::from(path));
+    }
+    find_map_file(&MAP_PATHS)
+}
+
+// First of the given paths naming a file.
+fn find_map_file(paths: &[&str]) -> Result<PathBuf, Error> {
+    for path in paths.iter().map(PathBuf::from) {
+        match fs::metadata(&path) {
+            Ok(metadata) if metadata.is_file() => return Ok(path),
+            Ok(_) => (),
+            Err(e) if e.kind() == io::ErrorKind::NotFound => (),
+            Err(e) => return Err(Error::Io(e)),
         }
     }
-    f.unwrap().map_err(|e| Box::new(e) as Box<dyn Error>)
+    Err(Error::MapNotFound(
+        paths.iter().map(PathBuf::from).collect(),
+    ))
 }
 
 impl Map {
-    /// Retrieve and parse the map data.
-    pub fn fetch() -> Result<Map, Box<dyn Error>> {
-        // Load up the JSON map data.
-        let map_file = find_map_file()?;
-        let gunzip = gzip::Decoder::new(map_file)?;
-        let map: json_repr::Map = serde_json::from_reader(gunzip)?;
+    /// Retrieve and parse the map data from the file given
+    /// by `map_path()`.
+    pub fn fetch() -> Result<Map, Error> {
+        Map::from_path(map_path()?)
+    }
+
+    /// Retrieve and parse the map data from the given file.
+    /// If a compiled map cache for the file is found at
+    /// `cache_path()` and is up to date, it is loaded instead.
+    pub fn from_path<P: AsRef<Path>>(path: P) -> Result<Map, Error> {
+        let path = path.as_ref();
+        let source = read_map_file(path)?;
+        if let Ok(map) = Map::from_cache_file(&cache_path(path), &source) {
+            return Ok(map);
+        }
+        Map::from_reader(&source[..])
+    }
 
+    /// Parse the map data from the given reader. The data may
+    /// be plain JSON, gzip-compressed JSON, or a compiled map
+    /// cache.
+    pub fn from_reader<R: Read>(reader: R) -> Result<Map, Error> {
+        // Look for the gzip or cache magic number.
+        let mut reader = BufReader::new(reader);
+        let magic = reader.fill_buf().map_err(Error::Io)?;
+        if magic.starts_with(CACHE_MAGIC) {
+            return Map::from_cache(reader, None);
+        }
+        let gzipped = magic.starts_with(&[0x1f, 0x8b]);
+        let map: json_repr::Map = if gzipped {
+            let gunzip = gzip::Decoder::new(reader).map_err(Error::Decompress)?;
+            serde_json::from_reader(gunzip).map_err(|e| {
+                if e.is_io() {
+                    Error::Decompress(e.into())
+                } else {
+                    Error::Json(e)
+                }
+            })?
+        } else {
+            serde_json::from_reader(reader)?
+        };
+        Map::from_json(map)
+    }
+
+    // Build the map from its parsed JSON representation,
+    // checking that every stargate leads somewhere. Systems
+    // are indexed in order of system id, so every load of
+    // the same data indexes them the same way. Systems
+    // without stargates, such as those of wormhole space, are
+    // kept: overlays may connect them.
+    fn from_json(map: json_repr::Map) -> Result<Map, Error> {
         // Set up the state and process the data.
         let mut by_system_id = HashMap::new();
         let mut by_name = HashMap::new();
         let mut systems = Vec::with_capacity(map.systems.len());
-        let mut system_index = 0;
-        for (system_id, system) in &map.systems {
+        let mut system_ids: Vec<&usize> = map.systems.keys().collect();
+        system_ids.sort();
+        for (system_index, system_id) in system_ids.into_iter().enumerate() {
+            let system = &map.systems[system_id];
             // Parse the current system id.
             let system_id = SystemId(*system_id);
 
             // Process the system stargates.
-            let stargates = match system.stargates {
-                None => continue,
-                Some(ref stargate_ids) => {
-                    stargate_ids
-                        .iter()
-                        .map(|s| SystemId(map.stargates[s].destination.system_id))
-                        .collect()
-                }
-            };
+            let stargate_ids = system.stargates.as_deref().unwrap_or_default();
+            let gates: Vec<Stargate> = stargate_ids
+                .iter()
+                .map(|s| {
+                    let stargate = map.stargates.get(s).ok_or(Error::DanglingStargate {
+                        system: system_id,
+                        stargate: *s,
+                    })?;
+                    let position = &stargate.position;
+                    Ok(Stargate {
+                        destination: SystemId(stargate.destination.system_id),
+                        position: Point {
+                            x: position.x,
+                            y: position.y,
+                            z: position.z,
+                        },
+                        kind: GateKind::Stargate,
+                        expires: None,
+                        size: None,
+                    })
+                })
+                .collect::<Result<_, Error>>()?;
+            let stargates = gates.iter().map(|g| g.destination).collect();
 
             // Save the system info and update the hashmaps.
             let system_info = SystemInfo {
                 system_id,
                 name: system.name.clone(),
+                security_status: system.security_status,
+                position: Point {
+                    x: system.position.x,
+                    y: system.position.y,
+                    z: system.position.z,
+                },
+                constellation_id: ConstellationId(system.constellation_id),
                 stargates,
+                gates,
                 system_index,
             };
             systems.push(system_info);
             by_system_id.insert(system_id, system_index);
             by_name.insert(system.name.clone(), system_index);
-
-            // Increase the system index for the next round.
-            system_index += 1;
         }
+        // Check that every stargate destination is a system
+        // of the map.
+        for system in &systems {
+            let stargate_ids = map.systems[&system.system_id.0]
+                .stargates
+                .as_deref()
+                .unwrap_or_default();
+            for (gate, stargate) in system.gates.iter().zip(stargate_ids) {
+                if !by_system_id.contains_key(&gate.destination) {
+                    return Err(Error::DanglingStargate {
+                        system: system.system_id,
+                        stargate: *stargate,
+                    });
+                }
+            }
+        }
+
+        // Process the constellations and regions.
+        let constellations = map
+            .constellations
+            .into_values()
+            .map(|c| {
+                let constellation_id = ConstellationId(c.constellation_id);
+                let info = ConstellationInfo {
+                    constellation_id,
+                    name: c.name,
+                    region_id: RegionId(c.region_id),
+                    systems: c.systems.into_iter().map(SystemId).collect(),
+                };
+                (constellation_id, info)
+            })
+            .collect();
+        let regions: HashMap<RegionId, RegionInfo> = map
+            .regions
+            .into_values()
+            .map(|r| {
+                let region_id = RegionId(r.region_id);
+                let info = RegionInfo {
+                    region_id,
+                    name: r.name,
+                    constellations: r.constellations.into_iter().map(ConstellationId).collect(),
+                };
+                (region_id, info)
+            })
+            .collect();
+
         // Return the now-completed map.
+        let (outgoing, incoming) = adjacency(&systems, &by_system_id);
         Ok(Map {
             systems,
             by_system_id,
             by_name,
+            constellations,
+            regions_by_name: region_names(&regions),
+            regions,
+            outgoing,
+            incoming,
         })
     }
 
+    // Build the map from its parts. Each system's
+    // `system_index` must be its index in `systems`.
+    pub(crate) fn from_parts(
+        systems: Vec<SystemInfo>,
+        constellations: Vec<ConstellationInfo>,
+        regions: Vec<RegionInfo>,
+    ) -> Map {
+        let by_system_id = systems
+            .iter()
+            .map(|s| (s.system_id, s.system_index))
+            .collect();
+        let by_name = systems
+            .iter()
+            .map(|s| (s.name.clone(), s.system_index))
+            .collect();
+        let constellations = constellations
+            .into_iter()
+            .map(|c| (c.constellation_id, c))
+            .collect();
+        let regions = regions.into_iter().map(|r| (r.region_id, r)).collect();
+        let (outgoing, incoming) = adjacency(&systems, &by_system_id);
+        Map {
+            systems,
+            by_system_id,
+            by_name,
+            constellations,
+            regions_by_name: region_names(&regions),
+            regions,
+            outgoing,
+            incoming,
+        }
+    }
+
     /// Return some reference to the system info for the system
     /// with the given name, if found.
     pub fn by_name<'a>(&'a self, name: &'a str) -> Option<&'a SystemInfo> {
         self.by_name.get(name).map(|i| &self.systems[*i])
     }
 
+    /// Return a reference to the system info for the system
+    /// with the given name, or an error if not found.
+    pub fn try_by_name(&self, name: &str) -> Result<&SystemInfo, Error> {
+        self.by_name
+            .get(name)
+            .map(|i| &self.systems[*i])
+            .ok_or_else(|| Error::UnknownSystemName(name.to_string()))
+    }
+
     /// Return some reference to the system info for the system
     /// with the given system id.
+    ///
+    /// # Panics
+    ///
+    /// Panics if there is no such system in the map. See
+    /// `try_by_system_id()`.
     pub fn by_system_id(&self, id: SystemId) -> &SystemInfo {
-        let i = self
-            .by_system_id
+        self.try_by_system_id(id)
+            .expect("by_system_id: invalid SystemId")
+    }
+
+    /// Return a reference to the system info for the system
+    /// with the given system id, or an error if not found.
+    pub fn try_by_system_id(&self, id: SystemId) -> Result<&SystemInfo, Error> {
+        self.by_system_id
             .get(&id)
-            .expect("by_system_id: invalid SystemId");
-        &self.systems[*i]
+            .map(|i| &self.systems[*i])
+            .ok_or(Error::UnknownSystemId(id))
     }
 
     /// Return an iterator over the system info of all
@@ -222,4 +633,287 @@ impl Map {
     pub fn systems_ref(&self) -> &[SystemInfo] {
         &self.systems
     }
+
+    /// System indices of the destinations of the stargates of
+    /// the system with index `i`, in stargate order.
+    pub fn outgoing(&self, i: usize) -> &[usize] {
+        &self.outgoing[i]
+    }
+
+    /// System indices of the systems with stargates leading
+    /// to the system with index `i`.
+    pub fn incoming(&self, i: usize) -> &[usize] {
+        &self.incoming[i]
+    }
+
+    // Add an outgoing gate to the system with index `i`. The
+    // gate's destination must be in the map.
+    fn add_gate(&mut self, i: usize, gate: Stargate) {
+        let j = self.by_system_id[&gate.destination];
+        self.systems[i].stargates.push(gate.destination);
+        self.systems[i].gates.push(gate);
+        self.outgoing[i].push(j);
+        self.incoming[j].push(i);
+    }
+
+    // Join each pair of system indices both ways by gates of
+    // the given kind at the star of each system, expiring and
+    // limited in ship size as given. Pairs already joined by
+    // that kind and size of gate are skipped, though their
+    // gates are kept as long as either joining would last.
+    // Returns the number of pairs joined.
+    pub(crate) fn add_links(&mut self, links: &[Link], kind: GateKind) -> usize {
+        let mut added = 0;
+        for &Link {
+            from,
+            to,
+            expires,
+            size,
+        } in links
+        {
+            if from == to {
+                continue;
+            }
+            let (a, b) = (self.systems[from].system_id, self.systems[to].system_id);
+            let mut joined = false;
+            for (i, destination) in [(from, b), (to, a)] {
+                for gate in &mut self.systems[i].gates {
+                    if gate.destination == destination && gate.kind == kind && gate.size == size {
+                        joined = true;
+                        gate.expires = match (gate.expires, expires) {
+                            (Some(x), Some(y)) => Some(x.max(y)),
+                            _ => None,
+                        };
+                    }
+                }
+            }
+            if joined {
+                continue;
+            }
+            for (i, destination) in [(from, b), (to, a)] {
+                let gate = Stargate {
+                    destination,
+                    position: Point {
+                        x: 0.0,
+                        y: 0.0,
+                        z: 0.0,
+                    },
+                    kind,
+                    expires,
+                    size,
+                };
+                self.add_gate(i, gate);
+            }
+            added += 1;
+        }
+        added
+    }
+
+    // Remove the gates for which `keep` is false, returning
+    // how many were removed.
+    pub(crate) fn retain_gates<F: Fn(&Stargate) -> bool>(&mut self, keep: F) -> usize {
+        let mut removed = 0;
+        for system in &mut self.systems {
+            let before = system.gates.len();
+            system.gates.retain(&keep);
+            removed += before - system.gates.len();
+            system.stargates = system.gates.iter().map(|g| g.destination).collect();
+        }
+        let (outgoing, incoming) = adjacency(&self.systems, &self.by_system_id);
+        self.outgoing = outgoing;
+        self.incoming = incoming;
+        removed
+    }
+
+    /// Return some reference to the info for the
+    /// constellation with the given id, if found.
+    pub fn constellation(&self, id: ConstellationId) -> Option<&ConstellationInfo> {
+        self.constellations.get(&id)
+    }
+
+    /// Return some reference to the info for the region with
+    /// the given id, if found.
+    pub fn region(&self, id: RegionId) -> Option<&RegionInfo> {
+        self.regions.get(&id)
+    }
+
+    /// Return some reference to the info for the region with
+    /// the given name, if found.
+    pub fn region_by_name(&self, name: &str) -> Option<&RegionInfo> {
+        self.region(*self.regions_by_name.get(name)?)
+    }
+
+    /// Return some reference to the info for the
+    /// constellation containing the given system, if the
+    /// system and its constellation are known.
+    pub fn constellation_of(&self, id: SystemId) -> Option<&ConstellationInfo> {
+        let system = self.try_by_system_id(id).ok()?;
+        self.constellation(system.constellation_id)
+    }
+
+    /// Return some reference to the info for the region
+    /// containing the given system, if the system and its
+    /// region are known.
+    pub fn region_of(&self, id: SystemId) -> Option<&RegionInfo> {
+        self.region(self.constellation_of(id)?.region_id)
+    }
+
+    /// Return an iterator over the info of all constellations
+    /// in the map, in no particular order.
+    pub fn constellations(&self) -> impl Iterator<Item = &ConstellationInfo> + '_ {
+        self.constellations.values()
+    }
+
+    /// Return an iterator over the info of all regions in the
+    /// map, in no particular order.
+    pub fn regions(&self) -> impl Iterator<Item = &RegionInfo> + '_ {
+        self.regions.values()
+    }
+
+    /// Return an iterator over the system info of all systems
+    /// in the given region.
+    pub fn systems_in_region(&self, id: RegionId) -> impl Iterator<Item = &SystemInfo> + '_ {
+        self.region(id)
+            .into_iter()
+            .flat_map(|r| r.constellations.iter())
+            .filter_map(|c| self.constellation(*c))
+            .flat_map(|c| c.systems.iter())
+            .filter_map(|s| self.by_system_id.get(s).map(|i| &self.systems[*i]))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::*;
+
+    #[test]
+    fn regions_and_constellations() {
+        let map = tiny_map();
+        let id = |name| map.by_name(name).unwrap().system_id;
+        assert_eq!("C-Eleven", map.constellation_of(id("Beta")).unwrap().name);
+        assert_eq!("Home", map.region_of(id("Alpha")).unwrap().name);
+        assert_eq!("Home", map.region_of(id("Beta")).unwrap().name);
+        assert_eq!("Away", map.region_of(id("Gamma")).unwrap().name);
+
+        let home = map.region_by_name("Home").unwrap().region_id;
+        let mut names: Vec<&str> = map
+            .systems_in_region(home)
+            .map(|s| s.name.as_str())
+            .collect();
+        names.sort();
+        assert_eq!(vec!["Alpha", "Beta"], names);
+        assert_eq!(2, map.regions().count());
+        assert_eq!(3, map.constellations().count());
+        assert!(map.region_by_name("Nowhere").is_none());
+        assert!(map.constellation_of(SystemId(4)).is_none());
+        assert!(map.region_of(SystemId(4)).is_none());
+    }
+
+    #[test]
+    fn map_without_regions() {
+        // Older map data has no constellations or regions.
+        let mut json: serde_json::Value = serde_json::from_str(TINY_MAP).unwrap();
+        let object = json.as_object_mut().unwrap();
+        object.remove("constellations");
+        object.remove("regions");
+        let map = Map::from_json(serde_json::from_value(json).unwrap()).unwrap();
+        let alpha = map.by_name("Alpha").unwrap();
+        assert_eq!(ConstellationId(10), alpha.constellation_id);
+        assert!(map.region_of(alpha.system_id).is_none());
+        assert_eq!(0, map.regions().count());
+    }
+
+    #[test]
+    fn map_from_reader() {
+        // Plain JSON.
+        let map = Map::from_reader(TINY_MAP.as_bytes()).unwrap();
+        assert_eq!(3, map.systems().count());
+
+        // Gzipped JSON.
+        let mut encoder = gzip::Encoder::new(Vec::new()).unwrap();
+        io::Write::write_all(&mut encoder, TINY_MAP.as_bytes()).unwrap();
+        let gzipped = encoder.finish().into_result().unwrap();
+        let map = Map::from_reader(&gzipped[..]).unwrap();
+        assert_eq!(3, map.systems().count());
+
+        // Truncated gzipped JSON.
+        let truncated = &gzipped[..gzipped.len() / 2];
+        assert!(matches!(
+            Map::from_reader(truncated),
+            Err(Error::Decompress(_))
+        ));
+
+        // Not a map at all.
+        assert!(matches!(Map::from_reader(&b"{}"[..]), Err(Error::Json(_))));
+    }
+
+    #[test]
+    fn map_from_missing_path() {
+        let path = Path::new("/nonexistent/eve-map.json.gz");
+        match Map::from_path(path) {
+            Err(Error::MapNotFound(paths)) => assert_eq!(vec![path.to_path_buf()], paths),
+            _ => panic!("found a missing map"),
+        }
+    }
+
+    #[test]
+    fn map_file_search() {
+        let dir = env!("CARGO_MANIFEST_DIR");
+        let missing = format!("{}/nonexistent.json.gz", dir);
+        let manifest = format!("{}/Cargo.toml", dir);
+        let found = find_map_file(&[&missing, dir, &manifest]).unwrap();
+        assert_eq!(PathBuf::from(&manifest), found);
+        match find_map_file(&[&missing]) {
+            Err(Error::MapNotFound(paths)) => assert_eq!(vec![PathBuf::from(&missing)], paths),
+            r => panic!("unexpected result {:?}", r),
+        }
+
+        // A path through a file cannot be examined.
+        let bad = format!("{}/eve-map.json.gz", manifest);
+        let e = find_map_file(&[&bad, &manifest]).unwrap_err();
+        assert!(matches!(e, Error::Io(_)));
+    }
+
+    #[test]
+    fn lookup_errors() {
+        let map = tiny_map();
+        assert!(matches!(
+            map.try_by_name("Delta"),
+            Err(Error::UnknownSystemName(ref name)) if name == "Delta"
+        ));
+        assert!(matches!(
+            map.try_by_system_id(SystemId(4)),
+            Err(Error::UnknownSystemId(SystemId(4)))
+        ));
+        let beta = map.try_by_name("Beta").unwrap().system_id;
+        assert_eq!("Beta", map.try_by_system_id(beta).unwrap().name);
+    }
+
+    #[test]
+    fn dangling_stargates() {
+        // A stargate leading to a missing system.
+        let mut json: serde_json::Value = serde_json::from_str(TINY_MAP).unwrap();
+        json["systems"].as_object_mut().unwrap().remove("3");
+        let result = Map::from_json(serde_json::from_value(json).unwrap());
+        assert!(matches!(
+            result,
+            Err(Error::DanglingStargate {
+                system: SystemId(2),
+                stargate: 103
+            })
+        ));
+
+        // A missing stargate.
+        let mut json: serde_json::Value = serde_json::from_str(TINY_MAP).unwrap();
+        json["stargates"].as_object_mut().unwrap().remove("101");
+        let result = Map::from_json(serde_json::from_value(json).unwrap());
+        assert!(matches!(
+            result,
+            Err(Error::DanglingStargate {
+                system: SystemId(1),
+                stargate: 101
+            })
+        ));
+    }
 }
@@ -0,0 +1,589 @@
+// Copyright © 2018 Po Huit
+// [This program is licensed under the "MIT License"]
+// Please see the file LICENSE in the source
+// distribution of this software for license terms.
+
+//! Route planning through waypoints for Plan B.
+
+use std::collections::HashSet;
+
+use crate::error::*;
+use crate::map::*;
+use crate::search::*;
+
+/// Largest number of stops for which `tour()` finds an
+/// exact optimum. Larger tours are planned heuristically.
+pub const EXACT_TOUR_LIMIT: usize = 12;
+
+/// A request for a route from a start to a goal, passing
+/// through the given waypoints in order.
+#[derive(Clone, Debug)]
+pub struct RouteRequest {
+    /// Starting system.
+    pub start: SystemId,
+    /// Intermediate systems to visit, in order.
+    pub waypoints: Vec<SystemId>,
+    /// Final system.
+    pub goal: SystemId,
+    /// Routing preference for every leg.
+    pub preference: Preference,
+    /// Penalty for systems disfavored by the preference.
+    pub penalty: usize,
+    /// Systems that no leg may pass through.
+    pub avoid: Avoid,
+}
+
+/// A route through waypoints, remembering where each leg
+/// ends.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct WaypointRoute {
+    /// Full route from start to goal.
+    pub route: Vec<SystemId>,
+    /// Index in `route` of the last system of each leg. The
+    /// last entry is always the index of the goal.
+    pub leg_ends: Vec<usize>,
+}
+
+impl RouteRequest {
+    /// Create a request for a shortest route from start to
+    /// goal with no waypoints.
+    pub fn new(start: SystemId, goal: SystemId) -> RouteRequest {
+        RouteRequest {
+            start,
+            waypoints: Vec::new(),
+            goal,
+            preference: Preference::Shortest,
+            penalty: DEFAULT_PENALTY,
+            avoid: Avoid::new(),
+        }
+    }
+
+    /// Find a route satisfying this request. If some leg has
+    /// no route, the error names that leg's endpoints. When
+    /// nothing is penalized, legs are found by the faster
+    /// `shortest_route()`.
+    pub fn route(&self, map: &Map) -> Result<WaypointRoute, Error> {
+        let mut stops = vec![self.start];
+        stops.extend(&self.waypoints);
+        stops.push(self.goal);
+
+        let shortest = self.preference == Preference::Shortest || self.penalty == 0;
+        let mut route = vec![self.start];
+        let mut leg_ends = Vec::with_capacity(stops.len() - 1);
+        for leg in stops.windows(2) {
+            let hops = if shortest {
+                shortest_route(map, leg[0], leg[1], &self.avoid)?
+            } else {
+                preferred_route(
+                    map,
+                    leg[0],
+                    leg[1],
+                    self.preference,
+                    self.penalty,
+                    &self.avoid,
+                )?
+            };
+            // Each leg starts where the last one ended.
+            route.extend(&hops[1..]);
+            leg_ends.push(route.len() - 1);
+        }
+        Ok(WaypointRoute { route, leg_ends })
+    }
+}
+
+impl WaypointRoute {
+    /// Return an iterator over the legs of the route. Each
+    /// leg includes both its endpoints, so adjacent legs share
+    /// a system.
+    pub fn legs(&self) -> impl Iterator<Item = &[SystemId]> + '_ {
+        let starts = std::iter::once(0).chain(self.leg_ends.iter().cloned());
+        starts
+            .zip(self.leg_ends.iter())
+            .map(move |(start, &end)| &self.route[start..=end])
+    }
+
+    /// Index in `route` of each waypoint, where one leg ends
+    /// and the next begins.
+    pub fn waypoints(&self) -> &[usize] {
+        &self.leg_ends[..self.leg_ends.len() - 1]
+    }
+}
+
+/// Find a fewest set of waypoints that makes the autopilot
+/// fly exactly the given route, assuming it picks routes the
+/// way `RouteRequest::route()` does with the given
+/// preference, penalty and avoidance set. The waypoints are
+/// in route order and exclude the start and goal.
+///
+/// The route must be connected by stargates and must not
+/// pass through avoided systems.
+pub fn sparse_waypoints(
+    map: &Map,
+    route: &[SystemId],
+    preference: Preference,
+    penalty: usize,
+    avoid: &Avoid,
+) -> Vec<SystemId> {
+    let n = route.len();
+    if n <= 2 {
+        return Vec::new();
+    }
+    let avoid = avoid.except(&[route[0], route[n - 1]]);
+    let index: Vec<usize> = route
+        .iter()
+        .map(|s| map.by_system_id(*s).system_index)
+        .collect();
+
+    // best[b]: fewest waypoints needed to fly route[..=b]
+    // with a waypoint at b, and the previous waypoint.
+    let mut best: Vec<Option<(usize, usize)>> = vec![None; n];
+    best[0] = Some((0, 0));
+    let shortest = preference == Preference::Shortest || penalty == 0;
+    for a in 0..n - 1 {
+        let (count, _) = best[a].expect("route not connected");
+
+        // Find every later system that the autopilot will
+        // reach from route[a] along the route itself, searching
+        // as `RouteRequest::route()` would.
+        let tree =
+            (!shortest).then(|| preferred_tree(map, route[a], None, preference, penalty, &avoid));
+        for (b, &i) in index.iter().enumerate().skip(a + 1) {
+            let leg = match &tree {
+                Some(tree) => tree_route(tree, i).map(|mut leg| {
+                    leg.reverse();
+                    leg
+                }),
+                None => bidirectional(map, index[a], i, &avoid),
+            };
+            let Some(leg) = leg else {
+                continue;
+            };
+            if leg[..] == index[a..=b] && best[b].is_none_or(|(c, _)| count + 1 < c) {
+                best[b] = Some((count + 1, a));
+            }
+        }
+    }
+
+    // Walk back from the goal collecting waypoints.
+    let mut waypoints = Vec::new();
+    let (_, mut a) = best[n - 1].expect("route not connected");
+    while a != 0 {
+        waypoints.push(route[a]);
+        a = best[a].unwrap().1;
+    }
+    waypoints.reverse();
+    waypoints
+}
+
+/// An order in which to visit a set of stops.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Tour {
+    /// Stops in visiting order, excluding the start and end.
+    pub stops: Vec<SystemId>,
+    /// Total jumps from start through the stops to the end.
+    pub jumps: usize,
+}
+
+/// Find an order in which to visit the given stops from
+/// `start`, finishing at `end` if given, that minimizes
+/// total jumps as measured by the APSP table. Duplicate
+/// stops, and stops at the start or end, are dropped.
+///
+/// With at most `EXACT_TOUR_LIMIT` stops the order is
+/// optimal; beyond that a nearest-neighbor tour is improved
+/// by 2-opt. Returns an error naming an unreachable pair of
+/// stops if there is one.
+pub fn tour(
+    map: &Map,
+    apsp: &APSPTable,
+    start: SystemId,
+    stops: &[SystemId],
+    end: Option<SystemId>,
+) -> Result<Tour, Error> {
+    // Collect the distinct stops.
+    let mut seen: HashSet<SystemId> = HashSet::new();
+    seen.insert(start);
+    seen.extend(end);
+    let stops: Vec<SystemId> = stops.iter().cloned().filter(|s| seen.insert(*s)).collect();
+
+    // Build the distance matrix: node 0 is the start, then
+    // the stops, then the end if any.
+    let mut nodes = vec![start];
+    nodes.extend(&stops);
+    nodes.extend(end);
+    let index: Vec<usize> = nodes
+        .iter()
+        .map(|s| Ok(map.try_by_system_id(*s)?.system_index))
+        .collect::<Result<_, Error>>()?;
+    let mut dist = Vec::with_capacity(nodes.len());
+    for (a, &i) in index.iter().enumerate() {
+        let mut row = Vec::with_capacity(nodes.len());
+        for (b, &j) in index.iter().enumerate() {
+            let d = apsp.dist(i, j).ok_or(Error::Unreachable {
+                start: nodes[a],
+                goal: nodes[b],
+                avoided: false,
+            })?;
+            row.push(d);
+        }
+        dist.push(row);
+    }
+
+    // Order the stops and report.
+    let (order, jumps) = if stops.len() <= EXACT_TOUR_LIMIT {
+        exact_tour(&dist, end.is_some())
+    } else {
+        heuristic_tour(&dist, end.is_some())
+    };
+    let stops = order.into_iter().map(|i| nodes[i]).collect();
+    Ok(Tour { stops, jumps })
+}
+
+// Cost of visiting the stop nodes in the given order from
+// node 0, finishing at the last node if `has_end`.
+fn tour_cost(dist: &[Vec<usize>], order: &[usize], has_end: bool) -> usize {
+    let mut cost = 0;
+    let mut cur = 0;
+    for &next in order {
+        cost += dist[cur][next];
+        cur = next;
+    }
+    if has_end {
+        cost += dist[cur][dist.len() - 1];
+    }
+    cost
+}
+
+// Optimal stop order by Held-Karp dynamic programming over
+// subsets of stops.
+fn exact_tour(dist: &[Vec<usize>], has_end: bool) -> (Vec<usize>, usize) {
+    let n = dist.len() - 1 - has_end as usize;
+    if n == 0 {
+        return (Vec::new(), tour_cost(dist, &[], has_end));
+    }
+
+    // best[mask][j]: cost of the cheapest path from the start
+    // through the stops in `mask`, ending at stop `j`.
+    let full = (1 << n) - 1;
+    let mut best = vec![vec![usize::MAX; n]; full + 1];
+    let mut parent = vec![vec![usize::MAX; n]; full + 1];
+    for j in 0..n {
+        best[1 << j][j] = dist[0][j + 1];
+    }
+    for mask in 1..=full {
+        for j in 0..n {
+            let cost = best[mask][j];
+            if cost == usize::MAX {
+                continue;
+            }
+            for k in 0..n {
+                if mask & (1 << k) != 0 {
+                    continue;
+                }
+                let next = mask | (1 << k);
+                let next_cost = cost + dist[j + 1][k + 1];
+                if next_cost < best[next][k] {
+                    best[next][k] = next_cost;
+                    parent[next][k] = j;
+                }
+            }
+        }
+    }
+
+    // Pick the best last stop and walk back.
+    let finish = |j: usize| {
+        let cost = best[full][j];
+        if has_end {
+            cost + dist[j + 1][n + 1]
+        } else {
+            cost
+        }
+    };
+    let mut last = (0..n).min_by_key(|&j| finish(j)).unwrap();
+    let cost = finish(last);
+    let mut order = Vec::with_capacity(n);
+    let mut mask = full;
+    while mask != 0 {
+        order.push(last + 1);
+        let prev = parent[mask][last];
+        mask &= !(1 << last);
+        last = prev;
+    }
+    order.reverse();
+    (order, cost)
+}
+
+// Stop order by nearest-neighbor construction improved by
+// 2-opt segment reversal until no reversal helps.
+fn heuristic_tour(dist: &[Vec<usize>], has_end: bool) -> (Vec<usize>, usize) {
+    let n = dist.len() - 1 - has_end as usize;
+
+    // Greedily visit the nearest unvisited stop.
+    let mut order = Vec::with_capacity(n);
+    let mut unvisited: Vec<usize> = (1..=n).collect();
+    let mut cur = 0;
+    while !unvisited.is_empty() {
+        let (i, _) = unvisited
+            .iter()
+            .enumerate()
+            .min_by_key(|(_, &s)| dist[cur][s])
+            .unwrap();
+        cur = unvisited.remove(i);
+        order.push(cur);
+    }
+
+    // Reverse segments while that shortens the tour. The
+    // path is framed by the start and, if any, the end.
+    let mut path = vec![0];
+    path.extend(&order);
+    if has_end {
+        path.push(n + 1);
+    }
+    let last = path.len() - 1;
+    let mut improved = true;
+    while improved {
+        improved = false;
+        for i in 1..=n {
+            for j in i + 1..=n {
+                // Reverse path[i..=j]: replace edges (i-1, i)
+                // and (j, j+1) with (i-1, j) and (i, j+1).
+                let before = dist[path[i - 1]][path[i]];
+                let after = dist[path[i - 1]][path[j]];
+                let (before, after) = if j < last {
+                    (
+                        before + dist[path[j]][path[j + 1]],
+                        after + dist[path[i]][path[j + 1]],
+                    )
+                } else {
+                    (before, after)
+                };
+                if after < before {
+                    path[i..=j].reverse();
+                    improved = true;
+                }
+            }
+        }
+    }
+
+    let order = path[1..=n].to_vec();
+    let cost = tour_cost(dist, &order, has_end);
+    (order, cost)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::*;
+
+    #[test]
+    fn route_via_waypoints() {
+        let map = shared_map();
+        let id = |name| find_system(map, name);
+        let avoid = Avoid::new();
+
+        let mut request = RouteRequest::new(id("Jita"), id("Amarr"));
+        request.waypoints = vec![id("Dodixie"), id("Rens")];
+        let planned = request.route(map).expect("no route");
+        assert_eq!(3, planned.leg_ends.len());
+        assert_eq!(planned.route.len() - 1, *planned.leg_ends.last().unwrap());
+        let waypoints: Vec<SystemId> = planned
+            .waypoints()
+            .iter()
+            .map(|&i| planned.route[i])
+            .collect();
+        assert_eq!(vec![id("Dodixie"), id("Rens")], waypoints);
+
+        let stops = [id("Jita"), id("Dodixie"), id("Rens"), id("Amarr")];
+        let legs: Vec<&[SystemId]> = planned.legs().collect();
+        assert_eq!(3, legs.len());
+        for (leg, ends) in legs.iter().zip(stops.windows(2)) {
+            let shortest = shortest_route(map, ends[0], ends[1], &avoid).unwrap();
+            assert_eq!(&shortest[..], *leg);
+        }
+
+        // Without waypoints, this is just the shortest route.
+        let request = RouteRequest::new(id("Jita"), id("Amarr"));
+        let planned = request.route(map).expect("no route");
+        let shortest = shortest_route(map, id("Jita"), id("Amarr"), &avoid).unwrap();
+        assert_eq!(shortest, planned.route);
+        assert_eq!(vec![shortest.len() - 1], planned.leg_ends);
+        assert!(planned.waypoints().is_empty());
+    }
+
+    #[test]
+    fn route_by_preference() {
+        let map = shared_map();
+        let (jita, amarr) = (find_system(map, "Jita"), find_system(map, "Amarr"));
+        let avoid = Avoid::new();
+        let shortest = shortest_route(map, jita, amarr, &avoid).unwrap();
+
+        // Without a penalty, any preference gives the
+        // bidirectional search's route.
+        let mut request = RouteRequest::new(jita, amarr);
+        request.preference = Preference::Safer;
+        request.penalty = 0;
+        assert_eq!(shortest, request.route(map).unwrap().route);
+
+        // With one, the route differs.
+        request.penalty = DEFAULT_PENALTY;
+        let safer = preferred_route(map, jita, amarr, Preference::Safer, DEFAULT_PENALTY, &avoid);
+        let planned = request.route(map).unwrap();
+        assert_eq!(safer.unwrap(), planned.route);
+        assert!(planned.route.len() > shortest.len());
+    }
+
+    #[test]
+    fn route_via_repeated_waypoint() {
+        let map = shared_map();
+        let id = |name| find_system(map, name);
+        let mut request = RouteRequest::new(id("Jita"), id("Perimeter"));
+        request.waypoints = vec![id("Jita")];
+        let planned = request.route(map).expect("no route");
+        assert_eq!(vec![id("Jita"), id("Perimeter")], planned.route);
+        assert_eq!(vec![0, 1], planned.leg_ends);
+        let legs: Vec<&[SystemId]> = planned.legs().collect();
+        assert_eq!(vec![&[id("Jita")][..], &planned.route[..]], legs);
+    }
+
+    // Brute-force the best tour cost over all stop orders.
+    fn brute_force(dist: &[Vec<usize>], order: &mut Vec<usize>, k: usize, has_end: bool) -> usize {
+        if k == order.len() {
+            return tour_cost(dist, order, has_end);
+        }
+        let mut best = usize::MAX;
+        for i in k..order.len() {
+            order.swap(k, i);
+            best = best.min(brute_force(dist, order, k + 1, has_end));
+            order.swap(k, i);
+        }
+        best
+    }
+
+    // Distances between some trade hubs and their neighbors.
+    fn hub_distances(end: bool) -> Vec<Vec<usize>> {
+        let (map, apsp) = map_apsp();
+        let mut names = vec![
+            "Jita",
+            "Amarr",
+            "Dodixie",
+            "Rens",
+            "Hek",
+            "Perimeter",
+            "Oursulaert",
+        ];
+        if end {
+            names.push("Tama");
+        }
+        let index: Vec<usize> = names
+            .iter()
+            .map(|n| map.by_system_id(find_system(map, n)).system_index)
+            .collect();
+        index
+            .iter()
+            .map(|&i| index.iter().map(|&j| apsp.dist(i, j).unwrap()).collect())
+            .collect()
+    }
+
+    #[test]
+    fn exact_tour_matches_brute_force() {
+        for has_end in [false, true] {
+            let dist = hub_distances(has_end);
+            let n = dist.len() - 1 - has_end as usize;
+            let (order, cost) = exact_tour(&dist, has_end);
+            assert_eq!(cost, tour_cost(&dist, &order, has_end));
+            let mut stops: Vec<usize> = (1..=n).collect();
+            assert_eq!(brute_force(&dist, &mut stops, 0, has_end), cost);
+            let (order, heuristic_cost) = heuristic_tour(&dist, has_end);
+            assert_eq!(heuristic_cost, tour_cost(&dist, &order, has_end));
+            assert!(heuristic_cost >= cost);
+        }
+    }
+
+    #[test]
+    fn tour_trade_hubs() {
+        let (map, apsp) = map_apsp();
+        let id = |name| find_system(map, name);
+        let stops = [
+            id("Dodixie"),
+            id("Amarr"),
+            id("Jita"),
+            id("Rens"),
+            id("Hek"),
+        ];
+        let tour = tour(map, apsp, id("Jita"), &stops, Some(id("Jita"))).unwrap();
+        assert_eq!(4, tour.stops.len());
+        assert!(!tour.stops.contains(&id("Jita")));
+
+        // The jump count is that of the route through the
+        // stops in the given order.
+        let mut request = RouteRequest::new(id("Jita"), id("Jita"));
+        request.waypoints = tour.stops.clone();
+        let planned = request.route(map).unwrap();
+        assert_eq!(tour.jumps, planned.route.len() - 1);
+    }
+
+    #[test]
+    fn tour_many_stops() {
+        // Enough stops to need the heuristic.
+        let (map, apsp) = map_apsp();
+        let jita = find_system(map, "Jita");
+        let stops: Vec<SystemId> = map
+            .systems()
+            .filter(|s| s.name.starts_with('A') && s.security_band() == SecurityBand::High)
+            .map(|s| s.system_id)
+            .take(2 * EXACT_TOUR_LIMIT)
+            .collect();
+        let tour = tour(map, apsp, jita, &stops, None).unwrap();
+        let mut sorted = tour.stops.clone();
+        sorted.sort();
+        let mut expected = stops.clone();
+        expected.sort();
+        assert_eq!(expected, sorted);
+    }
+
+    // Check that routing through the sparse waypoints flies
+    // exactly the given route, returning the waypoints.
+    fn check_sparse(map: &Map, route: &[SystemId], preference: Preference) -> Vec<SystemId> {
+        let waypoints = sparse_waypoints(map, route, preference, DEFAULT_PENALTY, &Avoid::new());
+        let mut request = RouteRequest::new(route[0], *route.last().unwrap());
+        request.waypoints = waypoints.clone();
+        request.preference = preference;
+        assert_eq!(route, &request.route(map).unwrap().route[..]);
+        waypoints
+    }
+
+    #[test]
+    fn sparse_shortest_route() {
+        let (map, _) = map_apsp();
+        let (jita, amarr) = (find_system(map, "Jita"), find_system(map, "Amarr"));
+        let route = shortest_route(map, jita, amarr, &Avoid::new()).unwrap();
+        assert!(check_sparse(map, &route, Preference::Shortest).is_empty());
+    }
+
+    #[test]
+    fn sparse_safer_route() {
+        // The safer route needs waypoints to fly with the
+        // autopilot set to shortest, but none when set to safer.
+        let (map, _) = map_apsp();
+        let (jita, amarr) = (find_system(map, "Jita"), find_system(map, "Amarr"));
+        let none = Avoid::new();
+        let route =
+            preferred_route(map, jita, amarr, Preference::Safer, DEFAULT_PENALTY, &none).unwrap();
+        assert!(!check_sparse(map, &route, Preference::Shortest).is_empty());
+        assert!(check_sparse(map, &route, Preference::Safer).is_empty());
+    }
+
+    #[test]
+    fn sparse_alt_routes() {
+        let (map, apsp) = map_apsp();
+        let (jita, rens) = (find_system(map, "Jita"), find_system(map, "Rens"));
+        let none = Avoid::new();
+        let routes = alt_routes(map, apsp, jita, rens, 4, 80.0, 25.0, 50.0, &none).unwrap();
+        assert!(routes.len() > 1);
+        for route in &routes[1..] {
+            let waypoints = check_sparse(map, route, Preference::Shortest);
+            assert!(!waypoints.is_empty());
+            assert!(waypoints.iter().all(|w| route.contains(w)));
+        }
+    }
+}
@@ -5,13 +5,17 @@
 
 //! Search functionality for Plan B.
 
-use std::cmp::Ordering;
 use std::collections::HashMap;
+use std::collections::HashSet;
 use std::collections::VecDeque;
+use std::str::FromStr;
+use std::thread;
 
-use ndarray::Array2;
+use serde::Deserialize;
 
+use crate::error::*;
 use crate::map::*;
+use crate::weighted::*;
 
 /// Results from a `diameter()` calculation.
 pub struct DiameterInfo {
@@ -22,90 +26,347 @@ pub struct DiameterInfo {
     pub longest: Vec<(SystemId, SystemId)>,
 }
 
-/// An entry in the all-pairs shortest-path table.
-#[derive(Clone)]
-pub struct Hop {
-    /// System indices of next hops.
-    pub next: Vec<usize>,
-    /// Distance from start to here.
-    pub dist: usize,
-}
-
-/// Table of all-pairs shortest paths.
-pub type APSPTable = Array2<Option<Hop>>;
-
-/// An intermediate step in the BFS shortest path search.
-#[derive(Clone, PartialOrd, Ord, PartialEq, Eq)]
-struct Waypoint {
-    /// Distance from start.
-    dist: usize,
-    /// System id of current system.
-    cur: SystemId,
-    /// Next hop back toward parent, if not at start.
-    parent: Option<SystemId>,
-}
-
-impl Waypoint {
-    /// Create a new waypoint; mild syntactic sugar.
-    fn new(dist: usize, cur: SystemId, parent: Option<SystemId>) -> Waypoint {
-        Waypoint { dist, cur, parent }
-    }
-}
-
-// Single-source shortest path via Breadth-First Search.
-// Returns a waypoint map for further processing.
-fn bfs(map: &Map, start: SystemId, goal: Option<SystemId>) -> HashMap<SystemId, Waypoint> {
-    // Set up data structures and run the search.
-    let mut q = VecDeque::with_capacity(map.systems_ref().len());
-    let mut closed = HashMap::new();
-    q.push_back(Waypoint::new(0, start, None));
-    loop {
-        // Examine best waypoint.
-        let waypoint = match q.pop_front() {
-            Some(waypoint) => waypoint,
-            None => return closed,
-        };
-        if closed.contains_key(&waypoint.cur) {
-            continue;
+/// Table of all-pairs shortest paths, indexed by system
+/// index. Only distances are stored, two bytes per pair; the
+/// next hops along shortest routes are derived on demand from
+/// the distances of neighboring systems.
+#[derive(Clone, PartialEq, Eq)]
+pub struct APSPTable {
+    /// Number of systems.
+    pub(crate) n: usize,
+    /// Distances in jumps, row-major, `UNREACHABLE` if none.
+    pub(crate) dist: Vec<u16>,
+}
+
+// Distance entry for a pair with no route.
+const UNREACHABLE: u16 = u16::MAX;
+
+impl APSPTable {
+    /// Number of systems in the table.
+    pub fn len(&self) -> usize {
+        self.n
+    }
+
+    /// True if the table has no systems.
+    pub fn is_empty(&self) -> bool {
+        self.n == 0
+    }
+
+    /// Distance in jumps from system index `i` to system
+    /// index `j`, if reachable.
+    pub fn dist(&self, i: usize, j: usize) -> Option<usize> {
+        match self.dist[i * self.n + j] {
+            UNREACHABLE => None,
+            d => Some(d as usize),
+        }
+    }
+}
+
+/// A set of systems that routes must not pass through. The
+/// start and goal of a route are never considered avoided.
+#[derive(Clone, Debug, Default)]
+pub struct Avoid {
+    systems: HashSet<SystemId>,
+}
+
+impl Avoid {
+    /// Create an empty avoidance set.
+    pub fn new() -> Avoid {
+        Avoid::default()
+    }
+
+    /// Avoid the system with the given system id.
+    pub fn insert(&mut self, system_id: SystemId) {
+        self.systems.insert(system_id);
+    }
+
+    /// Avoid the system with the given name, or return an
+    /// error if there is no such system in the map.
+    pub fn insert_name(&mut self, map: &Map, name: &str) -> Result<(), Error> {
+        let system = map.try_by_name(name)?;
+        self.insert(system.system_id);
+        Ok(())
+    }
+
+    /// True if the given system is avoided.
+    pub fn contains(&self, system_id: SystemId) -> bool {
+        self.systems.contains(&system_id)
+    }
+
+    /// True if no systems are avoided.
+    pub fn is_empty(&self) -> bool {
+        self.systems.is_empty()
+    }
+
+    /// Iterate over the avoided systems.
+    pub fn iter(&self) -> impl Iterator<Item = SystemId> + '_ {
+        self.systems.iter().cloned()
+    }
+
+    // Copy of this set with the given route endpoints
+    // removed.
+    pub(crate) fn except(&self, endpoints: &[SystemId]) -> Avoid {
+        let mut avoid = self.clone();
+        for system_id in endpoints {
+            avoid.systems.remove(system_id);
         }
-        closed.insert(waypoint.cur, waypoint.clone());
+        avoid
+    }
+}
+
+// Single-source shortest path tree over system indices,
+// giving for each reached system its distance in jumps from
+// `start` and its parent toward `start`.
+pub(crate) type Tree = Vec<Option<(usize, Option<usize>)>>;
 
+// Compute the BFS tree from `start`, never entering
+// avoided systems other than the goal, stopping early once
+// `goal` is reached.
+pub(crate) fn bfs_tree(map: &Map, start: SystemId, goal: Option<SystemId>, avoid: &Avoid) -> Tree {
+    let systems = map.systems_ref();
+    let start = map.by_system_id(start).system_index;
+    let goal = goal.map(|goal| map.by_system_id(goal).system_index);
+    let mut tree = vec![None; systems.len()];
+    let mut q = VecDeque::with_capacity(systems.len());
+    tree[start] = Some((0, None));
+    q.push_back(start);
+    while let Some(cur) = q.pop_front() {
         // If we have found the goal, we are done.
-        if goal == Some(waypoint.cur) {
-            return closed;
+        if goal == Some(cur) {
+            break;
         }
 
         // Open the children of the current system.
-        let map_info = map.by_system_id(waypoint.cur);
-        for child in map_info.stargates.iter() {
-            let child_waypoint = Waypoint::new(waypoint.dist + 1, *child, Some(waypoint.cur));
-            q.push_back(child_waypoint);
+        let dist = tree[cur].unwrap().0 + 1;
+        for &child in map.outgoing(cur) {
+            if tree[child].is_some()
+                || (avoid.contains(systems[child].system_id) && goal != Some(child))
+            {
+                continue;
+            }
+            tree[child] = Some((dist, Some(cur)));
+            q.push_back(child);
         }
     }
+    tree
 }
 
-/// Return a shortest route if one exists.
-pub fn shortest_route(map: &Map, start: SystemId, goal: SystemId) -> Option<Vec<SystemId>> {
-    // Find single-source shortest paths from start up to goal.
-    let waypoints = bfs(map, start, Some(goal));
+// No parent yet in a bidirectional search.
+const UNSEEN: usize = usize::MAX;
 
-    // Set up state and walk route.
-    let cur = waypoints.get(&goal)?;
-    let mut route = Vec::with_capacity(cur.dist);
-    let mut next_stop = cur.parent;
-    route.push(cur.cur);
-    while let Some(system_id) = next_stop {
-        route.push(system_id);
-        let cur = waypoints[&system_id].clone();
-        next_stop = cur.parent;
+// Shortest route between system indices by breadth-first
+// search from both ends at once, never entering avoided
+// systems other than the ends. The search grows the smaller
+// frontier a whole level at a time, and stops at the level
+// where the two searches first meet: every meeting on that
+// level gives a shortest route.
+pub(crate) fn bidirectional(
+    map: &Map,
+    start: usize,
+    goal: usize,
+    avoid: &Avoid,
+) -> Option<Vec<usize>> {
+    if start == goal {
+        return Some(vec![start]);
     }
+    let systems = map.systems_ref();
+    let allowed = |i: usize| {
+        avoid.is_empty() || i == start || i == goal || !avoid.contains(systems[i].system_id)
+    };
+
+    // Parents toward the start, and toward the goal.
+    let mut forward = vec![UNSEEN; systems.len()];
+    let mut backward = vec![UNSEEN; systems.len()];
+    forward[start] = start;
+    backward[goal] = goal;
+    let mut forward_frontier = vec![start];
+    let mut backward_frontier = vec![goal];
+    let mut next = Vec::new();
+    let meet = loop {
+        if forward_frontier.is_empty() || backward_frontier.is_empty() {
+            return None;
+        }
+        let grow_forward = forward_frontier.len() <= backward_frontier.len();
+        let (frontier, seen, other) = if grow_forward {
+            (&mut forward_frontier, &mut forward, &backward)
+        } else {
+            (&mut backward_frontier, &mut backward, &forward)
+        };
+        let mut meet = None;
+        for &cur in frontier.iter() {
+            let children = if grow_forward {
+                map.outgoing(cur)
+            } else {
+                map.incoming(cur)
+            };
+            for &child in children {
+                if seen[child] != UNSEEN || !allowed(child) {
+                    continue;
+                }
+                seen[child] = cur;
+                if other[child] != UNSEEN {
+                    meet = meet.or(Some(child));
+                }
+                next.push(child);
+            }
+        }
+        if let Some(meet) = meet {
+            break meet;
+        }
+        std::mem::swap(frontier, &mut next);
+        next.clear();
+    };
 
-    // Route was walked in reverse order. Reverse and return
-    // it.
+    // Join the two halves of the route at the meeting point.
+    let mut route = vec![meet];
+    let mut i = meet;
+    while i != start {
+        i = forward[i];
+        route.push(i);
+    }
     route.reverse();
+    let mut i = meet;
+    while i != goal {
+        i = backward[i];
+        route.push(i);
+    }
+    Some(route)
+}
+
+// Walk the tree from system index `i` back to its root, if
+// reached.
+pub(crate) fn tree_route(tree: &Tree, mut i: usize) -> Option<Vec<usize>> {
+    let mut route = vec![i];
+    while let (_, Some(parent)) = tree[i]? {
+        route.push(parent);
+        i = parent;
+    }
     Some(route)
 }
 
+// Check that both ends of a route are in the map.
+pub(crate) fn check_endpoints(map: &Map, start: SystemId, goal: SystemId) -> Result<(), Error> {
+    map.try_by_system_id(start)?;
+    map.try_by_system_id(goal)?;
+    Ok(())
+}
+
+// Error for a failed search from `start` to `goal`, noting
+// whether the avoided systems are to blame.
+pub(crate) fn unreachable(map: &Map, start: SystemId, goal: SystemId, avoid: &Avoid) -> Error {
+    let index = |system_id| map.by_system_id(system_id).system_index;
+    let avoided = !avoid.except(&[start, goal]).is_empty()
+        && bidirectional(map, index(start), index(goal), &Avoid::new()).is_some();
+    Error::Unreachable {
+        start,
+        goal,
+        avoided,
+    }
+}
+
+/// Return a shortest route not passing through avoided
+/// systems, or an error if there is none. The search runs
+/// from both ends at once, so is much faster than
+/// `preferred_route()` for distant systems, but may choose a
+/// different route among several of the same length.
+pub fn shortest_route(
+    map: &Map,
+    start: SystemId,
+    goal: SystemId,
+    avoid: &Avoid,
+) -> Result<Vec<SystemId>, Error> {
+    check_endpoints(map, start, goal)?;
+    let systems = map.systems_ref();
+    let index = |system_id| map.by_system_id(system_id).system_index;
+    bidirectional(map, index(start), index(goal), avoid)
+        .map(|route| route.iter().map(|i| systems[*i].system_id).collect())
+        .ok_or_else(|| unreachable(map, start, goal, avoid))
+}
+
+/// Routing preference, matching the in-game autopilot
+/// choices.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum Preference {
+    /// Minimize the number of jumps.
+    Shortest,
+    /// Avoid entering low-sec and null-sec systems.
+    Safer,
+    /// Avoid entering high-sec systems.
+    LessSecure,
+}
+
+impl Preference {
+    /// True if entering a system in the given band should be
+    /// penalized under this preference.
+    pub fn penalizes(self, band: SecurityBand) -> bool {
+        match self {
+            Preference::Shortest => false,
+            Preference::Safer => band != SecurityBand::High,
+            Preference::LessSecure => band == SecurityBand::High,
+        }
+    }
+}
+
+impl FromStr for Preference {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "shortest" => Ok(Preference::Shortest),
+            "safer" => Ok(Preference::Safer),
+            "less-secure" => Ok(Preference::LessSecure),
+            _ => Err(format!("unknown route preference {}", s)),
+        }
+    }
+}
+
+/// Default penalty, in jumps, for entering a system
+/// disfavored by a `Preference`.
+pub const DEFAULT_PENALTY: usize = 50;
+
+/// Return a best route under the given preference, or an
+/// error if there is none. Each jump costs one, plus
+/// `penalty` for entering a system whose security band the
+/// preference disfavors. Among routes of equal cost, fewer
+/// jumps win. The route will not pass through avoided
+/// systems.
+pub fn preferred_route(
+    map: &Map,
+    start: SystemId,
+    goal: SystemId,
+    preference: Preference,
+    penalty: usize,
+    avoid: &Avoid,
+) -> Result<Vec<SystemId>, Error> {
+    check_endpoints(map, start, goal)?;
+    let cost = SecurityCost {
+        preference,
+        penalty,
+    };
+    weighted_route(map, start, goal, &cost, avoid)
+        .map(|w| w.route)
+        .ok_or_else(|| unreachable(map, start, goal, avoid))
+}
+
+// Search tree from `start` under the given preference,
+// stopping early once `goal` is reached. Avoided systems
+// other than the goal are never entered.
+pub(crate) fn preferred_tree(
+    map: &Map,
+    start: SystemId,
+    goal: Option<SystemId>,
+    preference: Preference,
+    penalty: usize,
+    avoid: &Avoid,
+) -> Tree {
+    let cost = SecurityCost {
+        preference,
+        penalty,
+    };
+    weighted_tree(map, start, goal, &cost, avoid)
+}
+
 /// Compute and rank all admissable at-most-single-via
 /// alternative routes, returning up to *k* best. Based on a
 /// metric from
@@ -118,89 +379,366 @@ pub fn shortest_route(map: &Map, start: SystemId, goal: SystemId) -> Option<Vec<
 /// Optimization constraints are:
 ///
 /// * `max_routes`: Maximum number of routes to be returned
-///    (including shortest).
-/// * `sharing`: Maximum percentage of sharing of a route
-///    with the shortest route.
+///   (including shortest).
+/// * `sharing`: Maximum number of jumps a route may share
+///   with the routes already chosen, starting with the
+///   shortest, as a percentage of the shortest route length.
 /// * `local_opt`: Percentage of the shortest route length
-///    over which the route must be locally optimal (all subroutes
-///    of this length are shortest routes).
+///   over which the route must be locally optimal (all subroutes
+///   of this length are shortest routes).
 /// * `ub_stretch`: Percentage of "stretch" (extra jumps
-///    beyond shortest route) allowed along any subroute of a
-///    route.
+///   beyond shortest route) allowed along any subroute of a
+///   route.
 ///
 /// The objective function is a heuristic based on the
 /// settings of the optimization constraints.
 ///
-/// If there is no route from `start` to `goal`, `None` will
+/// No route will pass through an avoided system. The
+/// constraints are checked against APSP distances, which
+/// ignore avoidance, so are conservative when avoiding.
+///
+/// If there is no route from `start` to `goal`, an error will
 /// be returned. Otherwise, the route list is guaranteed to
 /// include at least the shortest route.
 #[allow(clippy::too_many_arguments)]
 pub fn alt_routes(
-    _map: &Map,
-    _apsp: &APSPTable,
-    _start: SystemId,
-    _goal: SystemId,
-    _max_routes: usize,
-    _sharing: f64,
-    _local_opt: f64,
-    _ub_stretch: f64,
-) -> Vec<Vec<SystemId>> {
-    unimplemented!("compute alternate routes")
-}
-
-/// Reconstruct shortest routes from start to goal, if any,
-/// using the APSP table.
-pub fn shortest_routes_apsp(
     map: &Map,
     apsp: &APSPTable,
     start: SystemId,
     goal: SystemId,
-) -> Option<Vec<Vec<SystemId>>> {
+    max_routes: usize,
+    sharing: f64,
+    local_opt: f64,
+    ub_stretch: f64,
+    avoid: &Avoid,
+) -> Result<Vec<Vec<SystemId>>, Error> {
+    // Find shortest route trees from both ends, and the
+    // shortest route and its length.
+    check_endpoints(map, start, goal)?;
     let systems = map.systems_ref();
-    let mut start = map.by_system_id(start).system_index;
-    let goal = map.by_system_id(goal).system_index;
-    let full_hop = match apsp[[start, goal]] {
-        Some(ref hop) => hop,
-        None => return None,
-    };
-    let mut dist = full_hop.dist;
-    let mut routes: Vec<Vec<SystemId>> = Vec::new();
-    let mut route: Vec<SystemId> = vec![systems[start].system_id];
-    while start != goal {
-        assert!(dist > 0);
-        let next_neighbors = &apsp[[start, goal]].as_ref().expect("missing hop").next;
-        let n = next_neighbors.len();
-        assert!(n > 0);
-        if n > 1 {
-            for neighbor in next_neighbors {
-                let neighbor = &systems[*neighbor];
-                let finishes =
-                    shortest_routes_apsp(map, apsp, neighbor.system_id, systems[goal].system_id)
-                        .expect("could not extend route");
-                for rest in finishes {
-                    assert!(rest.len() == dist);
-                    let mut full = route.clone();
-                    full.extend(rest);
-                    routes.push(full);
+    let avoid = avoid.except(&[start, goal]);
+    let t = map.by_system_id(goal).system_index;
+    let s_tree = bfs_tree(map, start, None, &avoid);
+    let t_tree = bfs_tree(map, goal, None, &avoid);
+    let mut opt = tree_route(&s_tree, t).ok_or_else(|| unreachable(map, start, goal, &avoid))?;
+    opt.reverse();
+    let opt_dist = opt.len() - 1;
+
+    // Translate the percentages into limits in jumps.
+    let max_shared = sharing / 100.0 * opt_dist as f64;
+    let max_local = local_opt / 100.0 * opt_dist as f64;
+    let stretch = 1.0 + ub_stretch / 100.0;
+
+    // Collect the single-via routes that are admissable on
+    // their own: simple, locally optimal and of bounded
+    // stretch. Sharing depends on the routes already chosen,
+    // so is dealt with below.
+    let mut candidates: Vec<Candidate> = Vec::new();
+    for v in 0..systems.len() {
+        let (Some((d_sv, _)), Some((d_vt, _))) = (s_tree[v], t_tree[v]) else {
+            continue;
+        };
+        let len = d_sv + d_vt;
+        if len as f64 > stretch * opt_dist as f64 {
+            continue;
+        }
+        let mut route = tree_route(&s_tree, v).expect("missing via route");
+        route.reverse();
+        route.pop();
+        route.extend(tree_route(&t_tree, v).expect("missing via route"));
+        if route == opt || candidates.iter().any(|c| c.route == route) {
+            continue;
+        }
+        if !is_simple(&route)
+            || !is_locally_optimal(apsp, &route, max_local)
+            || !is_bounded_stretch(apsp, &route, stretch)
+        {
+            continue;
+        }
+        let plateau = plateau(apsp, &route, d_sv);
+        candidates.push(Candidate { route, plateau });
+    }
+
+    // Greedily pick the best remaining admissable candidate
+    // until enough routes have been chosen.
+    let mut chosen_edges = route_edges(&opt);
+    let mut routes = vec![opt];
+    while routes.len() < max_routes {
+        let best = candidates
+            .iter()
+            .enumerate()
+            .filter_map(|(i, c)| {
+                let shared = route_edges(&c.route).intersection(&chosen_edges).count();
+                if shared as f64 > max_shared {
+                    return None;
                 }
+                let len = c.route.len() - 1;
+                let score = 2 * len + shared - c.plateau;
+                Some((score, len, i))
+            })
+            .min();
+        let Some((_, _, i)) = best else {
+            break;
+        };
+        let c = candidates.swap_remove(i);
+        chosen_edges.extend(route_edges(&c.route));
+        routes.push(c.route);
+    }
+
+    // Translate the routes back to system ids.
+    let routes = routes
+        .into_iter()
+        .map(|route| route.into_iter().map(|i| systems[i].system_id).collect())
+        .collect();
+    Ok(routes)
+}
+
+/// A single-via route under consideration by `alt_routes()`.
+struct Candidate {
+    /// System indices along the route.
+    route: Vec<usize>,
+    /// Length of the locally-optimal stretch around the via.
+    plateau: usize,
+}
+
+// True if no system is visited twice on the route.
+fn is_simple(route: &[usize]) -> bool {
+    let visited: HashSet<usize> = route.iter().cloned().collect();
+    visited.len() == route.len()
+}
+
+// True if every subroute of at most `max_local` jumps is a
+// shortest route.
+fn is_locally_optimal(apsp: &APSPTable, route: &[usize], max_local: f64) -> bool {
+    let n = route.len();
+    (0..n).all(|a| {
+        (a + 1..n)
+            .take_while(|b| (b - a) as f64 <= max_local)
+            .all(|b| apsp.dist(route[a], route[b]) == Some(b - a))
+    })
+}
+
+// True if no subroute is more than `stretch` times as long
+// as a shortest route between its endpoints.
+fn is_bounded_stretch(apsp: &APSPTable, route: &[usize], stretch: f64) -> bool {
+    let n = route.len();
+    (0..n).all(|a| {
+        (a + 1..n).all(|b| match apsp.dist(route[a], route[b]) {
+            Some(d) => (b - a) as f64 <= stretch * d as f64,
+            None => false,
+        })
+    })
+}
+
+// Length of the longest shortest subroute containing the
+// system at position `v` on the route.
+fn plateau(apsp: &APSPTable, route: &[usize], v: usize) -> usize {
+    let n = route.len();
+    let mut best = 0;
+    for a in 0..=v {
+        for b in (v..n).rev() {
+            if b - a <= best {
+                break;
+            }
+            if apsp.dist(route[a], route[b]) == Some(b - a) {
+                best = b - a;
+                break;
             }
-            return Some(routes);
         }
-        let next = next_neighbors[0];
-        route.push(systems[next].system_id);
-        dist -= 1;
-        start = next;
     }
-    routes.push(route);
-    Some(routes)
+    best
+}
+
+// Undirected edges of a route of system indices.
+fn route_edges(route: &[usize]) -> HashSet<(usize, usize)> {
+    route
+        .windows(2)
+        .map(|w| (w[0].min(w[1]), w[0].max(w[1])))
+        .collect()
+}
+
+/// Reconstruct shortest routes from start to goal using the
+/// APSP table, or return an error if there are none. The
+/// APSP table knows nothing of avoidance, so when avoiding
+/// systems the routes are instead reconstructed from a fresh
+/// search.
+///
+/// There may be very many shortest routes between distant
+/// systems: see `count_shortest_routes()` and
+/// `iter_shortest_routes()`.
+pub fn shortest_routes_apsp(
+    map: &Map,
+    apsp: &APSPTable,
+    start: SystemId,
+    goal: SystemId,
+    avoid: &Avoid,
+) -> Result<Vec<Vec<SystemId>>, Error> {
+    Ok(iter_shortest_routes(map, apsp, start, goal, avoid)?.collect())
+}
+
+/// Return an iterator over the shortest routes from start to
+/// goal, in the order `shortest_routes_apsp()` gives them, or
+/// an error if there are none. Routes are found one at a time
+/// as the iterator is advanced, so `take()` can be used to
+/// limit the work done.
+pub fn iter_shortest_routes<'a>(
+    map: &'a Map,
+    apsp: &'a APSPTable,
+    start: SystemId,
+    goal: SystemId,
+    avoid: &Avoid,
+) -> Result<ShortestRoutes<'a>, Error> {
+    let (start, dist) = goal_dist(map, apsp, start, goal, avoid)?;
+    let pending = next_closer(map, &dist, start);
+    Ok(ShortestRoutes {
+        map,
+        dist,
+        route: vec![start],
+        pending: vec![pending],
+    })
+}
+
+/// Count the shortest routes from start to goal without
+/// enumerating them, or return an error if there are none.
+/// The count saturates at `u64::MAX`.
+pub fn count_shortest_routes(
+    map: &Map,
+    apsp: &APSPTable,
+    start: SystemId,
+    goal: SystemId,
+    avoid: &Avoid,
+) -> Result<u64, Error> {
+    // Count the routes to the goal from system index `i`.
+    fn count(map: &Map, dist: &GoalDist, i: usize, counts: &mut HashMap<usize, u64>) -> u64 {
+        if dist.get(i) == Some(0) {
+            return 1;
+        }
+        if let Some(&c) = counts.get(&i) {
+            return c;
+        }
+        let c = next_closer(map, dist, i)
+            .into_iter()
+            .fold(0u64, |c, k| c.saturating_add(count(map, dist, k, counts)));
+        counts.insert(i, c);
+        c
+    }
+
+    let (start, dist) = goal_dist(map, apsp, start, goal, avoid)?;
+    Ok(count(map, &dist, start, &mut HashMap::new()))
+}
+
+// Distances in jumps to a goal by system index, if
+// reachable.
+enum GoalDist<'a> {
+    // From the APSP table, to the given system index.
+    Apsp(&'a APSPTable, usize),
+    // From a search tree grown from the goal.
+    Tree(Tree),
+}
+
+impl GoalDist<'_> {
+    fn get(&self, i: usize) -> Option<usize> {
+        match self {
+            GoalDist::Apsp(apsp, goal) => apsp.dist(i, *goal),
+            GoalDist::Tree(tree) => tree[i].map(|(d, _)| d),
+        }
+    }
+}
+
+// Distances to the goal for following shortest routes from
+// start to goal, together with the system index of start.
+// The APSP table knows nothing of avoidance, so when
+// avoiding systems a search tree from the goal is used.
+fn goal_dist<'a>(
+    map: &Map,
+    apsp: &'a APSPTable,
+    start: SystemId,
+    goal: SystemId,
+    avoid: &Avoid,
+) -> Result<(usize, GoalDist<'a>), Error> {
+    check_endpoints(map, start, goal)?;
+    let avoid = avoid.except(&[start, goal]);
+    let i = map.by_system_id(start).system_index;
+    let dist = if avoid.is_empty() {
+        GoalDist::Apsp(apsp, map.by_system_id(goal).system_index)
+    } else {
+        GoalDist::Tree(bfs_tree(map, goal, None, &avoid))
+    };
+    if dist.get(i).is_none() {
+        return Err(unreachable(map, start, goal, &avoid));
+    }
+    Ok((i, dist))
+}
+
+// Neighbors of system index `i` one jump closer to the goal,
+// in decreasing order of system index.
+fn next_closer(map: &Map, dist: &GoalDist, i: usize) -> Vec<usize> {
+    let Some(d) = dist.get(i).filter(|d| *d > 0) else {
+        return Vec::new();
+    };
+    let mut next: Vec<usize> = map.systems_ref()[i]
+        .stargates
+        .iter()
+        .map(|s| map.by_system_id(*s).system_index)
+        .filter(|k| dist.get(*k) == Some(d - 1))
+        .collect();
+    next.sort_unstable_by(|a, b| b.cmp(a));
+    next.dedup();
+    next
+}
+
+/// Iterator over shortest routes, from
+/// `iter_shortest_routes()`.
+pub struct ShortestRoutes<'a> {
+    map: &'a Map,
+    dist: GoalDist<'a>,
+    // System indices of the partial route being extended.
+    route: Vec<usize>,
+    // For each system on the partial route, the next hops
+    // not yet tried, to be tried from the end.
+    pending: Vec<Vec<usize>>,
+}
+
+impl Iterator for ShortestRoutes<'_> {
+    type Item = Vec<SystemId>;
+
+    fn next(&mut self) -> Option<Vec<SystemId>> {
+        let systems = self.map.systems_ref();
+        let ids = |route: &[usize]| route.iter().map(|i| systems[*i].system_id).collect();
+
+        // A route from the goal to itself.
+        if self.route.len() == 1 && self.dist.get(self.route[0]) == Some(0) {
+            let route = ids(&self.route);
+            self.route.clear();
+            self.pending.clear();
+            return Some(route);
+        }
+
+        // Extend the partial route depth-first, backing up
+        // when a system has no next hops left to try.
+        while let Some(pending) = self.pending.last_mut() {
+            let Some(k) = pending.pop() else {
+                self.pending.pop();
+                self.route.pop();
+                continue;
+            };
+            self.route.push(k);
+            if self.dist.get(k) == Some(0) {
+                let route = ids(&self.route);
+                self.route.pop();
+                return Some(route);
+            }
+            self.pending.push(next_closer(self.map, &self.dist, k));
+        }
+        None
+    }
 }
 
 /// Compute the diameter of New Eden, with other interesting
-/// info.
-pub fn diameter(map: &Map) -> DiameterInfo {
+/// info, from its APSP table.
+pub fn diameter(map: &Map, hops: &APSPTable) -> DiameterInfo {
     // Collect needed info.
     let systems = map.systems_ref();
-    let hops = apsp(map);
     let n = systems.len();
 
     // Reconstruct max diameter and incrementally update
@@ -209,8 +747,7 @@ pub fn diameter(map: &Map) -> DiameterInfo {
     let mut longest = Vec::new();
     for i in 0..n {
         for j in i + 1..n {
-            if let Some(ref hop) = &hops[[i, j]] {
-                let dist = hop.dist;
+            if let Some(dist) = hops.dist(i, j) {
                 if dist > diameter {
                     diameter = dist;
                     longest.clear();
@@ -228,56 +765,365 @@ pub fn diameter(map: &Map) -> DiameterInfo {
     DiameterInfo { diameter, longest }
 }
 
-/// Compute an all-pairs shortest-path route table.
+/// Compute an all-pairs shortest-path route table, using
+/// as many threads as there are CPUs.
 pub fn apsp(map: &Map) -> APSPTable {
+    apsp_threads(map, 0)
+}
+
+/// Compute an all-pairs shortest-path route table using the
+/// given number of threads, or as many as there are CPUs if
+/// `threads` is 0. The table does not depend on the number of
+/// threads.
+pub fn apsp_threads(map: &Map, threads: usize) -> APSPTable {
     // Set up necessary info.
-    let systems = map.systems_ref();
-    let n = systems.len();
-    let mut hops: Array2<Option<Hop>> = Array2::from_elem((n, n), None);
-
-    // Use iterated single-source shortest-path search to update
-    // all hops.
-    for start in systems {
-        let j = start.system_index;
-        let routes = bfs(map, start.system_id, None);
-        for waypoint in routes.values() {
-            let parent_info = match waypoint.parent {
-                None => {
-                    assert!(waypoint.cur == start.system_id);
-                    continue;
+    let n = map.systems_ref().len();
+    let mut dist = vec![UNREACHABLE; n * n];
+    if n == 0 {
+        return APSPTable { n, dist };
+    }
+
+    // Give each thread a block of rows to fill in by
+    // iterated breadth-first search.
+    let threads = match threads {
+        0 => thread::available_parallelism().map_or(1, |t| t.get()),
+        t => t,
+    };
+    let block = n.div_ceil(threads);
+    thread::scope(|scope| {
+        for (b, rows) in dist.chunks_mut(block * n).enumerate() {
+            scope.spawn(move || {
+                let mut q = VecDeque::with_capacity(n);
+                for (r, row) in rows.chunks_mut(n).enumerate() {
+                    bfs_row(map, b * block + r, row, &mut q);
                 }
-                Some(system_id) => map.by_system_id(system_id),
-            };
-            let parent_index = parent_info.system_index;
-            let cur_info = map.by_system_id(waypoint.cur);
-            let i = cur_info.system_index;
-            let old_hop = hops[[i, j]].take();
-            match old_hop {
-                Some(mut hop) => {
-                    match hop.dist.cmp(&waypoint.dist) {
-                        Ordering::Greater => {
-                            hop.dist = waypoint.dist;
-                            hop.next = vec![parent_index];
-                        }
-                        Ordering::Equal => {
-                            hop.next.push(parent_index);
-                            println!("multiple {:?}", hop.next);
-                        }
-                        Ordering::Less => (),
-                    }
-                    hops[[i, j]] = Some(hop);
+            });
+        }
+    });
+
+    // Return the constructed table.
+    APSPTable { n, dist }
+}
+
+// Fill in the APSP table row of distances from `start` by
+// breadth-first search.
+fn bfs_row(map: &Map, start: usize, row: &mut [u16], q: &mut VecDeque<usize>) {
+    row[start] = 0;
+    q.push_back(start);
+    while let Some(cur) = q.pop_front() {
+        let d = row[cur] + 1;
+        assert!(d < UNREACHABLE, "apsp: route too long");
+        for &next in map.outgoing(cur) {
+            if row[next] == UNREACHABLE {
+                row[next] = d;
+                q.push_back(next);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::*;
+
+    // Check the admissability constraints on a set of
+    // alternative routes.
+    fn check_alts(
+        start: &str,
+        goal: &str,
+        max_routes: usize,
+        sharing: f64,
+        ub_stretch: f64,
+        avoid: &[&str],
+    ) -> Vec<Vec<SystemId>> {
+        let (map, apsp) = map_apsp();
+        let start = find_system(map, start);
+        let goal = find_system(map, goal);
+        let mut avoid_set = Avoid::new();
+        for name in avoid {
+            avoid_set.insert_name(map, name).unwrap();
+        }
+        let routes = alt_routes(
+            map, apsp, start, goal, max_routes, sharing, 25.0, ub_stretch, &avoid_set,
+        )
+        .expect("no routes");
+        assert!(!routes.is_empty() && routes.len() <= max_routes);
+        let shortest = shortest_route(map, start, goal, &avoid_set).unwrap();
+        assert_eq!(shortest.len(), routes[0].len());
+        let index = |route: &[SystemId]| -> Vec<usize> {
+            route
+                .iter()
+                .map(|s| map.by_system_id(*s).system_index)
+                .collect()
+        };
+        // Edges of the routes checked so far.
+        let mut chosen_edges = route_edges(&index(&routes[0]));
+        let opt_dist = (routes[0].len() - 1) as f64;
+        for (i, route) in routes.iter().enumerate() {
+            assert_eq!(start, route[0]);
+            assert_eq!(goal, *route.last().unwrap());
+            assert!(is_simple(&index(route)));
+            assert!(!route.iter().any(|s| avoid_set.contains(*s)));
+            assert!(!routes[..i].contains(route));
+            let dist = (route.len() - 1) as f64;
+            assert!(dist <= (1.0 + ub_stretch / 100.0) * opt_dist);
+            if i > 0 {
+                let edges = route_edges(&index(route));
+                let shared = edges.intersection(&chosen_edges).count();
+                assert!(shared as f64 <= sharing / 100.0 * opt_dist);
+                chosen_edges.extend(edges);
+            }
+        }
+        routes
+    }
+
+    #[test]
+    fn alt_routes_jita_amarr() {
+        let routes = check_alts("Jita", "Amarr", 4, 80.0, 50.0, &[]);
+        assert_eq!(12, routes[0].len());
+        assert!(routes.len() > 1);
+    }
+
+    #[test]
+    fn alt_routes_no_stretch() {
+        // Jita → Amarr has two 11-jump routes.
+        let routes = check_alts("Jita", "Amarr", 4, 80.0, 0.0, &[]);
+        assert_eq!(2, routes.len());
+        assert!(routes.iter().all(|r| r.len() == 12));
+    }
+
+    #[test]
+    fn alt_routes_amarr_dodixie() {
+        let routes = check_alts("Amarr", "Dodixie", 4, 80.0, 50.0, &[]);
+        assert_eq!(4, routes.len());
+        assert_eq!(15, routes[0].len());
+    }
+
+    #[test]
+    fn alt_routes_max_one() {
+        let routes = check_alts("Jita", "Rens", 1, 80.0, 50.0, &[]);
+        assert_eq!(1, routes.len());
+    }
+
+    #[test]
+    fn alt_routes_adjacent() {
+        let routes = check_alts("Jita", "Perimeter", 4, 80.0, 50.0, &[]);
+        assert_eq!(1, routes.len());
+        assert_eq!(2, routes[0].len());
+    }
+
+    #[test]
+    fn alt_routes_same_system() {
+        let (map, apsp) = map_apsp();
+        let jita = find_system(map, "Jita");
+        let routes = alt_routes(map, apsp, jita, jita, 4, 80.0, 25.0, 50.0, &Avoid::new());
+        assert_eq!(vec![vec![jita]], routes.unwrap());
+    }
+
+    #[test]
+    fn alt_routes_avoiding() {
+        // Avoiding Ahbazon pushes Jita → Amarr off its usual
+        // pipe.
+        let routes = check_alts("Jita", "Amarr", 4, 80.0, 50.0, &["Ahbazon"]);
+        assert!(routes[0].len() > 12);
+    }
+
+    #[test]
+    fn shortest_route_avoiding() {
+        let (map, _) = map_apsp();
+        let jita = find_system(map, "Jita");
+        let amarr = find_system(map, "Amarr");
+        let shera = find_system(map, "Shera");
+        let mut avoid = Avoid::new();
+        avoid.insert(find_system(map, "Ahbazon"));
+        avoid.insert(shera);
+        let route = shortest_route(map, jita, amarr, &avoid).unwrap();
+        assert!(!route.iter().any(|s| avoid.contains(*s)));
+        let safer = preferred_route(map, jita, amarr, Preference::Safer, 50, &avoid).unwrap();
+        assert!(!safer.iter().any(|s| avoid.contains(*s)));
+
+        // Endpoints are never avoided.
+        let route = shortest_route(map, jita, shera, &avoid).unwrap();
+        assert_eq!(shera, *route.last().unwrap());
+    }
+
+    #[test]
+    fn shortest_routes_avoiding() {
+        let (map, apsp) = map_apsp();
+        let jita = find_system(map, "Jita");
+        let amarr = find_system(map, "Amarr");
+        let none = Avoid::new();
+        let routes = shortest_routes_apsp(map, apsp, jita, amarr, &none).unwrap();
+        assert!(routes.iter().all(|r| r.len() == 12));
+
+        // Both 11-jump routes pass through Ahbazon.
+        let mut avoid = Avoid::new();
+        avoid.insert(find_system(map, "Ahbazon"));
+        let routes = shortest_routes_apsp(map, apsp, jita, amarr, &avoid).unwrap();
+        let shortest = shortest_route(map, jita, amarr, &avoid).unwrap();
+        assert!(routes.contains(&shortest));
+        for route in &routes {
+            assert_eq!(shortest.len(), route.len());
+            assert!(!route.contains(&find_system(map, "Ahbazon")));
+        }
+    }
+
+    #[test]
+    fn apsp_matches_bfs() {
+        let (map, apsp) = map_apsp();
+        assert_eq!(map.systems().count(), apsp.len());
+        for start in map.systems().step_by(211) {
+            let tree = bfs_tree(map, start.system_id, None, &Avoid::new());
+            for (j, entry) in tree.iter().enumerate() {
+                assert_eq!(entry.map(|(d, _)| d), apsp.dist(start.system_index, j));
+            }
+        }
+    }
+
+    #[test]
+    fn bidirectional_matches_apsp() {
+        // Bidirectional routes are real routes, as short as
+        // the APSP table says, with and without avoidance.
+        let (map, apsp) = map_apsp();
+        let systems = map.systems_ref();
+        let mut avoid = Avoid::new();
+        for name in ["Ahbazon", "Niarja", "Uedama", "Tama"] {
+            avoid.insert(find_system(map, name));
+        }
+        for start in systems.iter().step_by(409) {
+            let tree = bfs_tree(map, start.system_id, None, &avoid);
+            for goal in systems.iter().step_by(97) {
+                let (i, j) = (start.system_index, goal.system_index);
+                let route = bidirectional(map, i, j, &Avoid::new());
+                assert_eq!(apsp.dist(i, j), route.as_ref().map(|r| r.len() - 1));
+                for hop in route.iter().flat_map(|r| r.windows(2)) {
+                    assert!(map.outgoing(hop[0]).contains(&hop[1]));
                 }
-                None => {
-                    let new_hop = Some(Hop {
-                        dist: waypoint.dist,
-                        next: vec![parent_index],
-                    });
-                    hops[[i, j]] = new_hop;
+                let route = bidirectional(map, i, j, &avoid);
+                assert_eq!(tree[j].map(|(d, _)| d), route.as_ref().map(|r| r.len() - 1));
+                for &k in route.iter().flatten() {
+                    assert!(k == i || k == j || !avoid.contains(systems[k].system_id));
                 }
             }
         }
     }
 
-    // Return the constructed table.
-    hops
+    #[test]
+    fn apsp_threads_agree() {
+        let (map, apsp) = map_apsp();
+        for threads in [1, 3] {
+            assert!(*apsp == apsp_threads(map, threads));
+        }
+    }
+
+    #[test]
+    fn apsp_next_closer() {
+        let (map, apsp) = map_apsp();
+        let index = |name| map.by_system_id(find_system(map, name)).system_index;
+        let (jita, amarr) = (index("Jita"), index("Amarr"));
+        let dist = GoalDist::Apsp(apsp, amarr);
+        assert!(next_closer(map, &GoalDist::Apsp(apsp, jita), jita).is_empty());
+
+        // The two 11-jump routes between them take exactly
+        // the next hops.
+        let routes = shortest_routes_apsp(
+            map,
+            apsp,
+            find_system(map, "Jita"),
+            find_system(map, "Amarr"),
+            &Avoid::new(),
+        )
+        .unwrap();
+        assert_eq!(2, routes.len());
+        assert_ne!(routes[0], routes[1]);
+        let mut firsts: Vec<usize> = routes
+            .iter()
+            .map(|r| map.by_system_id(r[1]).system_index)
+            .collect();
+        firsts.sort_unstable_by(|a, b| b.cmp(a));
+        firsts.dedup();
+        let next = next_closer(map, &dist, jita);
+        assert_eq!(firsts, next);
+        for k in next {
+            assert_eq!(apsp.dist(k, amarr), Some(10));
+        }
+    }
+
+    #[test]
+    fn count_matches_routes() {
+        let (map, apsp) = map_apsp();
+        let mut avoid = Avoid::new();
+        avoid.insert(find_system(map, "Ahbazon"));
+        for (start, goal) in [
+            ("Jita", "Amarr"),
+            ("Amarr", "Dodixie"),
+            ("Jita", "Jita"),
+            ("Jita", "Perimeter"),
+        ] {
+            let (start, goal) = (find_system(map, start), find_system(map, goal));
+            for avoid in [&Avoid::new(), &avoid] {
+                let routes = shortest_routes_apsp(map, apsp, start, goal, avoid).unwrap();
+                let count = count_shortest_routes(map, apsp, start, goal, avoid).unwrap();
+                assert_eq!(routes.len() as u64, count);
+                let lazy: Vec<Vec<SystemId>> = iter_shortest_routes(map, apsp, start, goal, avoid)
+                    .unwrap()
+                    .collect();
+                assert_eq!(routes, lazy);
+            }
+        }
+    }
+
+    #[test]
+    fn many_shortest_routes() {
+        // Long null-sec routes branch a lot: count them, and
+        // take just a few without enumerating the rest.
+        let (map, apsp) = map_apsp();
+        let start = find_system(map, "E7-WSY");
+        let goal = find_system(map, "RXTY-4");
+        let none = Avoid::new();
+        let count = count_shortest_routes(map, apsp, start, goal, &none).unwrap();
+        assert_eq!(120, count);
+        let index = |s| map.by_system_id(s).system_index;
+        let hops = apsp.dist(index(start), index(goal)).unwrap();
+        let routes: Vec<Vec<SystemId>> = iter_shortest_routes(map, apsp, start, goal, &none)
+            .unwrap()
+            .take(10)
+            .collect();
+        assert_eq!(10, routes.len());
+        for (i, route) in routes.iter().enumerate() {
+            assert_eq!(hops + 1, route.len());
+            assert_eq!((start, goal), (route[0], *route.last().unwrap()));
+            assert!(!routes[..i].contains(route));
+        }
+    }
+
+    #[test]
+    fn unreachable_when_avoiding() {
+        // Jita's only neighbors cut it off from everything.
+        let (map, apsp) = map_apsp();
+        let jita = map.by_name("Jita").unwrap();
+        let amarr = find_system(map, "Amarr");
+        let mut avoid = Avoid::new();
+        for system_id in &jita.stargates {
+            avoid.insert(*system_id);
+        }
+        let jita = jita.system_id;
+        let blocked = |e| {
+            matches!(e, Error::Unreachable { start, goal, avoided: true }
+                     if start == jita && goal == amarr)
+        };
+        assert!(blocked(
+            shortest_route(map, jita, amarr, &avoid).unwrap_err()
+        ));
+        assert!(blocked(
+            shortest_routes_apsp(map, apsp, jita, amarr, &avoid).unwrap_err()
+        ));
+        assert!(blocked(
+            alt_routes(map, apsp, jita, amarr, 4, 80.0, 25.0, 50.0, &avoid).unwrap_err()
+        ));
+        assert!(blocked(
+            count_shortest_routes(map, apsp, jita, amarr, &avoid).unwrap_err()
+        ));
+    }
 }
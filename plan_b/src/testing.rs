@@ -0,0 +1,74 @@
+// Copyright © 2018 Po Huit
+// [This program is licensed under the "MIT License"]
+// Please see the file LICENSE in the source
+// distribution of this software for license terms.
+
+// Shared fixtures for Plan B tests.
+
+use std::sync::OnceLock;
+
+use crate::map::*;
+use crate::search::*;
+
+// Loading the map takes a while, so share one across tests
+// that don't need APSP.
+pub fn shared_map() -> &'static Map {
+    static MAP: OnceLock<Map> = OnceLock::new();
+    MAP.get_or_init(|| Map::fetch().expect("could not open map"))
+}
+
+// The APSP table is expensive, so share one across tests.
+pub fn map_apsp() -> &'static (Map, APSPTable) {
+    static MAP_APSP: OnceLock<(Map, APSPTable)> = OnceLock::new();
+    MAP_APSP.get_or_init(|| {
+        let map = Map::fetch().expect("could not open map");
+        let apsp = apsp(&map);
+        (map, apsp)
+    })
+}
+
+// Look up a system id by name, which must be in the map.
+pub fn find_system(map: &Map, name: &str) -> SystemId {
+    map.by_name(name).expect("unknown system").system_id
+}
+
+// A tiny map: two constellations of one region, and a
+// constellation of another region.
+pub const TINY_MAP: &str = r#"{
+    "systems": {
+        "1": {"constellation_id": 10, "name": "Alpha", "security_status": 0.9,
+              "position": {"x": 0, "y": 0, "z": 0}, "system_id": 1, "stargates": [101]},
+        "2": {"constellation_id": 11, "name": "Beta", "security_status": 0.3,
+              "position": {"x": 1, "y": 0, "z": 0}, "system_id": 2, "stargates": [102, 103]},
+        "3": {"constellation_id": 12, "name": "Gamma", "security_status": -0.2,
+              "position": {"x": 2, "y": 0, "z": 0}, "system_id": 3, "stargates": [104]}
+    },
+    "stargates": {
+        "101": {"destination": {"stargate_id": 102, "system_id": 2}, "name": "Stargate (Beta)",
+                "position": {"x": 0, "y": 0, "z": 0}, "stargate_id": 101, "system_id": 1,
+                "type_id": 16},
+        "102": {"destination": {"stargate_id": 101, "system_id": 1}, "name": "Stargate (Alpha)",
+                "position": {"x": 0, "y": 0, "z": 0}, "stargate_id": 102, "system_id": 2,
+                "type_id": 16},
+        "103": {"destination": {"stargate_id": 104, "system_id": 3}, "name": "Stargate (Gamma)",
+                "position": {"x": 0, "y": 0, "z": 0}, "stargate_id": 103, "system_id": 2,
+                "type_id": 16},
+        "104": {"destination": {"stargate_id": 103, "system_id": 2}, "name": "Stargate (Beta)",
+                "position": {"x": 0, "y": 0, "z": 0}, "stargate_id": 104, "system_id": 3,
+                "type_id": 16}
+    },
+    "constellations": {
+        "10": {"constellation_id": 10, "name": "C-Ten", "region_id": 100, "systems": [1]},
+        "11": {"constellation_id": 11, "name": "C-Eleven", "region_id": 100, "systems": [2]},
+        "12": {"constellation_id": 12, "name": "C-Twelve", "region_id": 200, "systems": [3]}
+    },
+    "regions": {
+        "100": {"region_id": 100, "name": "Home", "constellations": [10, 11]},
+        "200": {"region_id": 200, "name": "Away", "constellations": [12]}
+    }
+}"#;
+
+// Build the tiny map.
+pub fn tiny_map() -> Map {
+    Map::from_reader(TINY_MAP.as_bytes()).expect("bad tiny map")
+}
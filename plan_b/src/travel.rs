@@ -0,0 +1,299 @@
+// Copyright © 2018 Po Huit
+// [This program is licensed under the "MIT License"]
+// Please see the file LICENSE in the source
+// distribution of this software for license terms.
+
+//! Travel-time estimation for Plan B.
+
+use std::str::FromStr;
+
+use crate::error::*;
+use crate::map::*;
+use crate::search::*;
+use crate::weighted::*;
+
+/// One astronomical unit, in meters.
+pub const AU: f64 = 149_597_870_700.0;
+
+/// Broad classes of ship, for picking a `TravelModel`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ShipClass {
+    /// Frigates and their variants.
+    Frigate,
+    /// Destroyers and their variants.
+    Destroyer,
+    /// Cruisers and their variants.
+    Cruiser,
+    /// Battlecruisers.
+    Battlecruiser,
+    /// Battleships.
+    Battleship,
+    /// Industrials (haulers).
+    Industrial,
+    /// Freighters.
+    Freighter,
+}
+
+impl FromStr for ShipClass {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "frigate" => Ok(ShipClass::Frigate),
+            "destroyer" => Ok(ShipClass::Destroyer),
+            "cruiser" => Ok(ShipClass::Cruiser),
+            "battlecruiser" => Ok(ShipClass::Battlecruiser),
+            "battleship" => Ok(ShipClass::Battleship),
+            "industrial" => Ok(ShipClass::Industrial),
+            "freighter" => Ok(ShipClass::Freighter),
+            _ => Err(format!("unknown ship class {}", s)),
+        }
+    }
+}
+
+/// Parameters for estimating how long a ship takes to fly a
+/// route. Warp follows the exponential acceleration and
+/// deceleration model used by the EVE client.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct TravelModel {
+    /// Seconds from activating a gate until the ship can act
+    /// in the next system.
+    pub jump_time: f64,
+    /// Seconds to align and enter warp.
+    pub align_time: f64,
+    /// Maximum warp speed, in AU/s.
+    pub warp_speed: f64,
+    /// Warp acceleration rate, per second.
+    pub warp_accel: f64,
+    /// Warp deceleration rate, per second.
+    pub warp_decel: f64,
+    /// Speed at which the ship drops out of warp, in m/s.
+    pub warp_dropout: f64,
+}
+
+impl TravelModel {
+    /// Build a model from a warp speed in AU/s and an align
+    /// time in seconds, deriving the warp acceleration and
+    /// deceleration as the client does.
+    pub fn new(warp_speed: f64, align_time: f64) -> TravelModel {
+        TravelModel {
+            jump_time: 10.0,
+            align_time,
+            warp_speed,
+            warp_accel: warp_speed,
+            warp_decel: (warp_speed / 3.0).min(2.0),
+            warp_dropout: 100.0,
+        }
+    }
+
+    /// Rough model for an unfitted T1 hull of the given class.
+    pub fn for_class(class: ShipClass) -> TravelModel {
+        match class {
+            ShipClass::Frigate => TravelModel::new(5.0, 3.0),
+            ShipClass::Destroyer => TravelModel::new(4.5, 4.5),
+            ShipClass::Cruiser => TravelModel::new(3.0, 6.0),
+            ShipClass::Battlecruiser => TravelModel::new(2.7, 8.0),
+            ShipClass::Battleship => TravelModel::new(2.0, 11.0),
+            ShipClass::Industrial => TravelModel::new(4.5, 9.0),
+            ShipClass::Freighter => TravelModel::new(1.37, 40.0),
+        }
+    }
+
+    /// Estimated seconds spent in warp over the given
+    /// distance in meters.
+    ///
+    /// This is the model set out in the EVE University wiki's
+    /// "Warp" article. Accelerating, the ship has covered `e^(ka t)`
+    /// meters after `t` seconds, so is moving at `ka e^(ka t)`
+    /// m/s: it reaches speed `v` after `ln(v / ka) / ka`
+    /// seconds, having covered `v / ka` meters. Decelerating
+    /// from `v`, its speed falls as `v e^(-kd t)`, so it drops
+    /// out of warp after `ln(v / dropout) / kd` seconds, having
+    /// covered about `v / kd` meters.
+    pub fn warp_time(&self, distance: f64) -> f64 {
+        if distance <= 0.0 {
+            return 0.0;
+        }
+        let (ka, kd) = (self.warp_accel, self.warp_decel);
+        let max_speed = self.warp_speed * AU;
+        let accel_distance = max_speed / ka;
+        let decel_distance = max_speed / kd;
+        if distance >= accel_distance + decel_distance {
+            let cruise = (distance - accel_distance - decel_distance) / max_speed;
+            (max_speed / ka).ln() / ka + cruise + (max_speed / self.warp_dropout).ln() / kd
+        } else {
+            // Short warp: the ship never reaches full speed.
+            let peak = distance * ka * kd / (ka + kd);
+            (peak / ka).ln().max(0.0) / ka + (peak / self.warp_dropout).ln().max(0.0) / kd
+        }
+    }
+
+    /// Estimated seconds to fly from system `cur` through its
+    /// gate `exit`, having arrived at `cur` through its gate
+    /// from `prev`. With no `prev`, the ship is assumed to
+    /// start on grid with the exit gate.
+    pub fn hop_time(&self, cur: &SystemInfo, prev: Option<SystemId>, exit: &Stargate) -> f64 {
+        let entry = prev.and_then(|prev| cur.gate_to(prev));
+        match entry {
+            None => self.jump_time,
+            Some(entry) => {
+                let distance = entry.position.distance(&exit.position);
+                self.align_time + self.warp_time(distance) + self.jump_time
+            }
+        }
+    }
+}
+
+/// A route with estimated travel times.
+#[derive(Clone, Debug, PartialEq)]
+pub struct TimedRoute {
+    /// Systems along the route.
+    pub route: Vec<SystemId>,
+    /// Estimated seconds from arriving at each system of the
+    /// route until arriving at the next.
+    pub hop_times: Vec<f64>,
+}
+
+impl TimedRoute {
+    /// Estimated seconds for the whole route.
+    pub fn total_time(&self) -> f64 {
+        self.hop_times.iter().sum()
+    }
+}
+
+/// Estimate travel times along an existing route, or `None`
+/// if it is not connected by gates. Where several gates lead
+/// to the next system, the first is taken.
+pub fn timed_route(map: &Map, route: &[SystemId], model: &TravelModel) -> Option<TimedRoute> {
+    let mut hop_times = Vec::with_capacity(route.len().saturating_sub(1));
+    for (i, hop) in route.windows(2).enumerate() {
+        let prev = i.checked_sub(1).map(|p| route[p]);
+        let cur = map.try_by_system_id(hop[0]).ok()?;
+        hop_times.push(model.hop_time(cur, prev, cur.gate_to(hop[1])?));
+    }
+    Some(TimedRoute {
+        route: route.to_vec(),
+        hop_times,
+    })
+}
+
+impl Cost for TravelModel {
+    fn cost(
+        &self,
+        from: &SystemInfo,
+        gate: &Stargate,
+        _: &SystemInfo,
+        prev: Option<&SystemInfo>,
+    ) -> Option<f64> {
+        Some(self.hop_time(from, prev.map(|p| p.system_id), gate))
+    }
+
+    fn tracks_entry(&self) -> bool {
+        true
+    }
+}
+
+/// Return a route minimizing estimated travel time under the
+/// given model, with per-hop times, or an error if there is
+/// none. The route will not pass through avoided systems.
+pub fn fastest_route(
+    map: &Map,
+    start: SystemId,
+    goal: SystemId,
+    model: &TravelModel,
+    avoid: &Avoid,
+) -> Result<TimedRoute, Error> {
+    check_endpoints(map, start, goal)?;
+    let weighted = weighted_route(map, start, goal, model, avoid)
+        .ok_or_else(|| unreachable(map, start, goal, avoid))?;
+    Ok(TimedRoute {
+        route: weighted.route,
+        hop_times: weighted.costs,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::*;
+
+    #[test]
+    fn warp_times() {
+        let model = TravelModel::for_class(ShipClass::Cruiser);
+        assert_eq!(0.0, model.warp_time(0.0));
+        let mut last = 0.0;
+        for au in [0.01, 0.1, 1.0, 10.0, 50.0] {
+            let t = model.warp_time(au * AU);
+            assert!(t > last);
+            last = t;
+        }
+        // A long warp is dominated by cruising at full speed.
+        let t = model.warp_time(100.0 * AU);
+        assert!(t > 100.0 / 3.0 && t < 100.0 / 3.0 + 30.0);
+
+        // Worked by hand: a 3 AU/s cruiser warping 10 AU takes
+        // 8.6 s to reach full speed over 1 AU, cruises 6 AU in
+        // 2 s, and takes 22.2 s to slow down over the last 3 AU.
+        let t = model.warp_time(10.0 * AU);
+        assert!((t - 32.8).abs() < 0.1);
+    }
+
+    #[test]
+    fn hop_time_uses_given_gate() {
+        // A bridge far from Beta's stargates takes a warp to
+        // reach, where the stargate to Alpha does not.
+        let map = tiny_map();
+        let beta = map.by_name("Beta").unwrap();
+        let (alpha, gamma) = (find_system(&map, "Alpha"), find_system(&map, "Gamma"));
+        let model = TravelModel::for_class(ShipClass::Cruiser);
+        let stargate = beta.gate_to(alpha).unwrap();
+        let bridge = Stargate {
+            position: Point {
+                x: AU,
+                y: 0.0,
+                z: 0.0,
+            },
+            kind: GateKind::Bridge,
+            ..*stargate
+        };
+        let near = model.hop_time(beta, Some(gamma), stargate);
+        let far = model.hop_time(beta, Some(gamma), &bridge);
+        assert!((far - near - model.warp_time(AU)).abs() < 1e-6);
+
+        // Routes off the gates cannot be timed.
+        assert_eq!(None, timed_route(&map, &[alpha, gamma], &model));
+    }
+
+    #[test]
+    fn fastest_route_beats_shortest() {
+        let map = shared_map();
+        let (jita, amarr) = (find_system(map, "Jita"), find_system(map, "Amarr"));
+        let avoid = Avoid::new();
+        for class in [ShipClass::Frigate, ShipClass::Freighter] {
+            let model = TravelModel::for_class(class);
+            let fastest = fastest_route(map, jita, amarr, &model, &avoid).unwrap();
+            assert_eq!(jita, fastest.route[0]);
+            assert_eq!(amarr, *fastest.route.last().unwrap());
+            assert_eq!(fastest.route.len() - 1, fastest.hop_times.len());
+
+            // Re-timing the route agrees with the search.
+            let retimed = timed_route(map, &fastest.route, &model).unwrap();
+            assert!((retimed.total_time() - fastest.total_time()).abs() < 1e-6);
+
+            let shortest = shortest_route(map, jita, amarr, &avoid).unwrap();
+            let shortest = timed_route(map, &shortest, &model).unwrap();
+            assert!(fastest.total_time() <= shortest.total_time() + 1e-6);
+        }
+    }
+
+    #[test]
+    fn fastest_route_avoiding() {
+        let map = shared_map();
+        let (jita, amarr) = (find_system(map, "Jita"), find_system(map, "Amarr"));
+        let mut avoid = Avoid::new();
+        avoid.insert(find_system(map, "Ahbazon"));
+        let model = TravelModel::for_class(ShipClass::Industrial);
+        let fastest = fastest_route(map, jita, amarr, &model, &avoid).unwrap();
+        assert!(!fastest.route.iter().any(|s| avoid.contains(*s)));
+    }
+}
@@ -0,0 +1,606 @@
+// Copyright © 2018 Po Huit
+// [This program is licensed under the "MIT License"]
+// Please see the file LICENSE in the source
+// distribution of this software for license terms.
+
+//! Weighted route search with pluggable costs for Plan B.
+
+use std::cmp::Ordering;
+use std::collections::BinaryHeap;
+use std::collections::HashMap;
+use std::collections::HashSet;
+
+use crate::error::*;
+use crate::map::*;
+use crate::search::*;
+
+/// A cost function for weighted route search.
+pub trait Cost {
+    /// Cost of jumping from system `from` through its
+    /// stargate `gate` into system `to`, or `None` if the jump
+    /// is forbidden. Costs must not be negative. `prev` is the
+    /// system `from` was entered from, if any; it is only
+    /// supplied when `tracks_entry()` is true.
+    fn cost(
+        &self,
+        from: &SystemInfo,
+        gate: &Stargate,
+        to: &SystemInfo,
+        prev: Option<&SystemInfo>,
+    ) -> Option<f64>;
+
+    /// True if `cost()` depends on how `from` was entered.
+    /// The search must then distinguish arrivals at a system
+    /// by entry gate, which is slower.
+    fn tracks_entry(&self) -> bool {
+        false
+    }
+
+    /// Lower bound on the cost from `from` to `goal`, for A*
+    /// search. The default of zero gives Dijkstra search.
+    fn estimate(&self, _from: &SystemInfo, _goal: &SystemInfo) -> f64 {
+        0.0
+    }
+}
+
+/// Every jump costs one: minimizes jumps.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct JumpCost;
+
+impl Cost for JumpCost {
+    fn cost(
+        &self,
+        _: &SystemInfo,
+        _: &Stargate,
+        _: &SystemInfo,
+        _: Option<&SystemInfo>,
+    ) -> Option<f64> {
+        Some(1.0)
+    }
+}
+
+/// Every jump costs one, plus `penalty` for entering a system
+/// whose security band `preference` disfavors.
+#[derive(Clone, Copy, Debug)]
+pub struct SecurityCost {
+    /// Routing preference.
+    pub preference: Preference,
+    /// Penalty for entering a disfavored system.
+    pub penalty: usize,
+}
+
+impl Cost for SecurityCost {
+    fn cost(
+        &self,
+        _: &SystemInfo,
+        _: &Stargate,
+        to: &SystemInfo,
+        _: Option<&SystemInfo>,
+    ) -> Option<f64> {
+        if self.preference.penalizes(to.security_band()) {
+            Some(1.0 + self.penalty as f64)
+        } else {
+            Some(1.0)
+        }
+    }
+}
+
+/// A route found by weighted search.
+#[derive(Clone, Debug, PartialEq)]
+pub struct WeightedRoute {
+    /// Systems along the route.
+    pub route: Vec<SystemId>,
+    /// Cost of each jump along the route.
+    pub costs: Vec<f64>,
+}
+
+impl WeightedRoute {
+    /// Total cost of the route.
+    pub fn total_cost(&self) -> f64 {
+        self.costs.iter().sum()
+    }
+}
+
+// Priority queue entry of a cost and a system index,
+// yielding the lowest cost first.
+pub(crate) struct CostEntry(pub(crate) f64, pub(crate) usize);
+
+impl PartialEq for CostEntry {
+    fn eq(&self, other: &Self) -> bool {
+        self.cmp(other) == Ordering::Equal
+    }
+}
+
+impl Eq for CostEntry {}
+
+impl Ord for CostEntry {
+    fn cmp(&self, other: &Self) -> Ordering {
+        other
+            .0
+            .total_cmp(&self.0)
+            .then_with(|| other.1.cmp(&self.1))
+    }
+}
+
+impl PartialOrd for CostEntry {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+// Cost of every jump, by source then destination system
+// index. Where the cost depends on how the source was
+// entered, the cheapest entry is used, which keeps the
+// landmark costs lower bounds.
+pub(crate) fn jump_costs<C: Cost + ?Sized>(map: &Map, cost: &C) -> Vec<Vec<(usize, f64)>> {
+    let systems = map.systems_ref();
+    systems
+        .iter()
+        .map(|from| {
+            let mut prevs = vec![None];
+            if cost.tracks_entry() {
+                prevs.extend(
+                    map.incoming(from.system_index)
+                        .iter()
+                        .map(|&p| Some(&systems[p])),
+                );
+            }
+            let mut jumps: Vec<(usize, f64)> = Vec::new();
+            for (gate, &j) in from.gates.iter().zip(map.outgoing(from.system_index)) {
+                let to = &systems[j];
+                let best = prevs
+                    .iter()
+                    .filter_map(|&prev| cost.cost(from, gate, to, prev))
+                    .min_by(f64::total_cmp);
+                if let Some(best) = best {
+                    jumps.push((j, best));
+                }
+            }
+            jumps
+        })
+        .collect()
+}
+
+// Search state: a system index, and the index of the system
+// it was entered from if the cost tracks entries.
+type State = (usize, Option<usize>);
+
+// Best known arrival at a state: cost, jumps and parent.
+type Label = (f64, usize, Option<State>);
+
+// Priority queue entry. The heap yields the lowest priority
+// first, then fewest jumps, then earliest discovered; with
+// unit costs this visits systems in breadth-first order.
+struct Entry {
+    priority: f64,
+    cost: f64,
+    jumps: usize,
+    seq: usize,
+    state: State,
+}
+
+impl PartialEq for Entry {
+    fn eq(&self, other: &Self) -> bool {
+        self.cmp(other) == Ordering::Equal
+    }
+}
+
+impl Eq for Entry {}
+
+impl Ord for Entry {
+    fn cmp(&self, other: &Self) -> Ordering {
+        other
+            .priority
+            .total_cmp(&self.priority)
+            .then_with(|| other.jumps.cmp(&self.jumps))
+            .then_with(|| other.seq.cmp(&self.seq))
+    }
+}
+
+impl PartialOrd for Entry {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+// Run a Dijkstra or A* search from `start`, stopping once
+// `goal` is reached. Avoided systems other than the goal are
+// never entered. Returns the labels of all states reached
+// and the goal state if found.
+fn search<C: Cost + ?Sized>(
+    map: &Map,
+    start: SystemId,
+    goal: Option<SystemId>,
+    cost: &C,
+    avoid: &Avoid,
+) -> (HashMap<State, Label>, Option<State>) {
+    let systems = map.systems_ref();
+    let tracks_entry = cost.tracks_entry();
+    let goal_info = goal.map(|goal| map.by_system_id(goal));
+    let estimate = |i: usize| match goal_info {
+        Some(goal) => cost.estimate(&systems[i], goal),
+        None => 0.0,
+    };
+
+    // Set up data structures and run the search.
+    let start = (map.by_system_id(start).system_index, None);
+    let mut labels: HashMap<State, Label> = HashMap::new();
+    let mut q = BinaryHeap::new();
+    let mut seq = 0;
+    labels.insert(start, (0.0, 0, None));
+    q.push(Entry {
+        priority: estimate(start.0),
+        cost: 0.0,
+        jumps: 0,
+        seq,
+        state: start,
+    });
+    while let Some(entry) = q.pop() {
+        // Skip stale entries.
+        let state = entry.state;
+        let (best_cost, best_jumps, _) = labels[&state];
+        if (best_cost, best_jumps) != (entry.cost, entry.jumps) {
+            continue;
+        }
+
+        // If we have found the goal, we are done.
+        let (cur, prev) = state;
+        let cur_info = &systems[cur];
+        if goal_info.map(|g| g.system_index) == Some(cur) {
+            return (labels, Some(state));
+        }
+
+        // Open the children of the current state.
+        let prev_info = prev.map(|p| &systems[p]);
+        for gate in &cur_info.gates {
+            let child = gate.destination;
+            if avoid.contains(child) && goal != Some(child) {
+                continue;
+            }
+            let child_info = map.by_system_id(child);
+            let Some(jump_cost) = cost.cost(cur_info, gate, child_info, prev_info) else {
+                continue;
+            };
+            let child_state = (
+                child_info.system_index,
+                if tracks_entry { Some(cur) } else { None },
+            );
+            let child_cost = entry.cost + jump_cost;
+            let child_jumps = entry.jumps + 1;
+            let better = match labels.get(&child_state) {
+                Some(&(c, j, _)) => (child_cost, child_jumps) < (c, j),
+                None => true,
+            };
+            if better {
+                labels.insert(child_state, (child_cost, child_jumps, Some(state)));
+                seq += 1;
+                q.push(Entry {
+                    priority: child_cost + estimate(child_state.0),
+                    cost: child_cost,
+                    jumps: child_jumps,
+                    seq,
+                    state: child_state,
+                });
+            }
+        }
+    }
+    (labels, None)
+}
+
+/// Return a route minimizing the given cost, with per-jump
+/// costs, if one exists. The route will not pass through
+/// avoided systems.
+pub fn weighted_route<C: Cost + ?Sized>(
+    map: &Map,
+    start: SystemId,
+    goal: SystemId,
+    cost: &C,
+    avoid: &Avoid,
+) -> Option<WeightedRoute> {
+    let (labels, found) = search(map, start, Some(goal), cost, avoid);
+
+    // Walk the states back from the goal.
+    let systems = map.systems_ref();
+    let mut state = found?;
+    let mut route = vec![systems[state.0].system_id];
+    let mut costs = Vec::new();
+    while let (c, _, Some(parent)) = labels[&state] {
+        route.push(systems[parent.0].system_id);
+        costs.push(c - labels[&parent].0);
+        state = parent;
+    }
+    route.reverse();
+    costs.reverse();
+    Some(WeightedRoute { route, costs })
+}
+
+// Search tree from `start` minimizing the given cost, which
+// must not track entries, stopping early once `goal` is
+// reached.
+pub(crate) fn weighted_tree<C: Cost + ?Sized>(
+    map: &Map,
+    start: SystemId,
+    goal: Option<SystemId>,
+    cost: &C,
+    avoid: &Avoid,
+) -> Tree {
+    assert!(!cost.tracks_entry(), "weighted_tree: cost tracks entries");
+    let (labels, _) = search(map, start, goal, cost, avoid);
+    let mut tree = vec![None; map.systems_ref().len()];
+    for ((i, _), (_, jumps, parent)) in labels {
+        tree[i] = Some((jumps, parent.map(|(p, _)| p)));
+    }
+    tree
+}
+
+// A cost that forbids jumps between certain pairs of system
+// indices, for the spur searches of `k_shortest_routes()`.
+// Forbidding jumps can only make routes dearer, so the
+// underlying estimate remains a lower bound.
+struct Banned<'a, C: ?Sized> {
+    cost: &'a C,
+    jumps: &'a HashSet<(usize, usize)>,
+}
+
+impl<C: Cost + ?Sized> Cost for Banned<'_, C> {
+    fn cost(
+        &self,
+        from: &SystemInfo,
+        gate: &Stargate,
+        to: &SystemInfo,
+        prev: Option<&SystemInfo>,
+    ) -> Option<f64> {
+        if self.jumps.contains(&(from.system_index, to.system_index)) {
+            return None;
+        }
+        self.cost.cost(from, gate, to, prev)
+    }
+
+    fn estimate(&self, from: &SystemInfo, goal: &SystemInfo) -> f64 {
+        self.cost.estimate(from, goal)
+    }
+}
+
+// Order routes by cost, then jumps, then system indices.
+fn route_order(map: &Map, a: &WeightedRoute, b: &WeightedRoute) -> Ordering {
+    let indices = |r: &WeightedRoute| -> Vec<usize> {
+        r.route
+            .iter()
+            .map(|s| map.by_system_id(*s).system_index)
+            .collect()
+    };
+    a.total_cost()
+        .total_cmp(&b.total_cost())
+        .then_with(|| a.route.len().cmp(&b.route.len()))
+        .then_with(|| indices(a).cmp(&indices(b)))
+}
+
+/// Return up to `k` cheapest loopless routes from `start` to
+/// `goal` under the given cost, cheapest first, using Yen's
+/// algorithm:
+///
+/// > *Finding the K Shortest Loopless Paths in a Network*  
+/// > Jin Y. Yen  
+/// > Management Science 17(11), 1971  
+///
+/// Routes of equal cost are ordered by fewest jumps, then
+/// by comparing their system indices in turn, so the result
+/// is deterministic. No route will pass through an avoided
+/// system. Returns an error if there is no route at all, or
+/// if the cost tracks entries.
+pub fn k_shortest_routes<C: Cost + ?Sized>(
+    map: &Map,
+    start: SystemId,
+    goal: SystemId,
+    k: usize,
+    cost: &C,
+    avoid: &Avoid,
+) -> Result<Vec<WeightedRoute>, Error> {
+    check_endpoints(map, start, goal)?;
+    if cost.tracks_entry() {
+        return Err(Error::BadCost(
+            "k shortest routes need a cost that does not track entries".to_string(),
+        ));
+    }
+    let first = weighted_route(map, start, goal, cost, avoid)
+        .ok_or_else(|| unreachable(map, start, goal, avoid))?;
+    let mut routes = vec![first];
+    let mut candidates: Vec<WeightedRoute> = Vec::new();
+    let index = |s: &SystemId| map.by_system_id(*s).system_index;
+    while routes.len() < k {
+        // Deviate from the last route found at each system
+        // along it in turn.
+        let last = routes.last().unwrap().clone();
+        for spur in 0..last.route.len() - 1 {
+            let root = &last.route[..=spur];
+
+            // Forbid the next jump of every route found so far
+            // that shares this root, and re-entering the root.
+            let mut jumps = HashSet::new();
+            for route in &routes {
+                if route.route.len() > spur + 1 && route.route[..=spur] == *root {
+                    jumps.insert((index(&route.route[spur]), index(&route.route[spur + 1])));
+                }
+            }
+            let mut spur_avoid = avoid.clone();
+            for &system_id in &root[..spur] {
+                spur_avoid.insert(system_id);
+            }
+            let banned = Banned {
+                cost,
+                jumps: &jumps,
+            };
+            let Some(tail) = weighted_route(map, root[spur], goal, &banned, &spur_avoid) else {
+                continue;
+            };
+
+            // Join the root to the spur route.
+            let mut route = root.to_vec();
+            route.extend_from_slice(&tail.route[1..]);
+            let mut costs = last.costs[..spur].to_vec();
+            costs.extend(tail.costs);
+            let candidate = WeightedRoute { route, costs };
+            if !candidates.contains(&candidate) && !routes.contains(&candidate) {
+                candidates.push(candidate);
+            }
+        }
+
+        // Take the best candidate as the next route.
+        let best =
+            (0..candidates.len()).min_by(|&a, &b| route_order(map, &candidates[a], &candidates[b]));
+        let Some(best) = best else {
+            break;
+        };
+        routes.push(candidates.swap_remove(best));
+    }
+
+    // The first route may tie with later ones: put ties in
+    // order.
+    routes.sort_by(|a, b| route_order(map, a, b));
+    routes.truncate(k);
+    Ok(routes)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::*;
+    use crate::travel::*;
+
+    #[test]
+    fn unit_cost_matches_bfs() {
+        // Weighted search with unit costs finds exactly the
+        // routes breadth-first search does.
+        let map = shared_map();
+        let avoid = Avoid::new();
+        let jita = find_system(map, "Jita");
+        for goal in map.systems().step_by(97) {
+            let tree = bfs_tree(map, jita, Some(goal.system_id), &avoid);
+            let bfs_route = tree_route(&tree, goal.system_index).map(|mut route| {
+                route.reverse();
+                route
+            });
+            let weighted = weighted_route(map, jita, goal.system_id, &JumpCost, &avoid);
+            let weighted = weighted.map(|w| {
+                w.route
+                    .iter()
+                    .map(|s| map.by_system_id(*s).system_index)
+                    .collect()
+            });
+            assert_eq!(bfs_route, weighted);
+        }
+    }
+
+    #[test]
+    fn security_cost_per_jump() {
+        let map = shared_map();
+        let avoid = Avoid::new();
+        let (jita, amarr) = (find_system(map, "Jita"), find_system(map, "Amarr"));
+        let cost = SecurityCost {
+            preference: Preference::Safer,
+            penalty: DEFAULT_PENALTY,
+        };
+        let safer = weighted_route(map, jita, amarr, &cost, &avoid).unwrap();
+        assert_eq!(safer.route.len() - 1, safer.costs.len());
+        assert_eq!((safer.route.len() - 1) as f64, safer.total_cost());
+    }
+
+    // A* with a lower bound finds routes as cheap as without.
+    struct Halved;
+
+    impl Cost for Halved {
+        fn cost(
+            &self,
+            _: &SystemInfo,
+            _: &Stargate,
+            _: &SystemInfo,
+            _: Option<&SystemInfo>,
+        ) -> Option<f64> {
+            Some(1.0)
+        }
+
+        fn estimate(&self, from: &SystemInfo, goal: &SystemInfo) -> f64 {
+            if from.system_index == goal.system_index {
+                0.0
+            } else {
+                0.5
+            }
+        }
+    }
+
+    #[test]
+    fn astar_estimate() {
+        let map = shared_map();
+        let avoid = Avoid::new();
+        let (start, goal) = (find_system(map, "B-GC1T"), find_system(map, "2UK4-N"));
+        let astar = weighted_route(map, start, goal, &Halved, &avoid).unwrap();
+        let plain = weighted_route(map, start, goal, &JumpCost, &avoid).unwrap();
+        assert_eq!(plain.total_cost(), astar.total_cost());
+        assert_eq!(79.0, plain.total_cost());
+    }
+
+    #[test]
+    fn k_shortest_jita_amarr() {
+        // The shortest routes come first, in the order
+        // `iter_shortest_routes()` gives them; the rest are
+        // loopless, distinct and no shorter.
+        let (map, apsp) = map_apsp();
+        let avoid = Avoid::new();
+        let (jita, amarr) = (find_system(map, "Jita"), find_system(map, "Amarr"));
+        let routes = k_shortest_routes(map, jita, amarr, 6, &JumpCost, &avoid).unwrap();
+        assert_eq!(6, routes.len());
+        let shortest: Vec<Vec<SystemId>> = iter_shortest_routes(map, apsp, jita, amarr, &avoid)
+            .unwrap()
+            .collect();
+        let found: Vec<Vec<SystemId>> = routes.iter().map(|r| r.route.clone()).collect();
+        assert_eq!(shortest[..], found[..shortest.len()]);
+        for (i, route) in routes.iter().enumerate() {
+            assert_eq!(
+                (jita, amarr),
+                (route.route[0], *route.route.last().unwrap())
+            );
+            let systems: HashSet<SystemId> = route.route.iter().cloned().collect();
+            assert_eq!(route.route.len(), systems.len());
+            assert!(!found[..i].contains(&route.route));
+            if i > 0 {
+                assert!(routes[i - 1].total_cost() <= route.total_cost());
+            }
+        }
+        assert!(routes[5].total_cost() > routes[0].total_cost());
+        let again = k_shortest_routes(map, jita, amarr, 6, &JumpCost, &avoid).unwrap();
+        assert_eq!(routes, again);
+    }
+
+    #[test]
+    fn k_shortest_avoiding() {
+        let map = shared_map();
+        let (jita, amarr) = (find_system(map, "Jita"), find_system(map, "Amarr"));
+        let mut avoid = Avoid::new();
+        avoid.insert(find_system(map, "Niarja"));
+        let cost = SecurityCost {
+            preference: Preference::Safer,
+            penalty: DEFAULT_PENALTY,
+        };
+        let routes = k_shortest_routes(map, jita, amarr, 3, &cost, &avoid).unwrap();
+        assert_eq!(3, routes.len());
+        let best = weighted_route(map, jita, amarr, &cost, &avoid).unwrap();
+        assert_eq!(best.total_cost(), routes[0].total_cost());
+        for route in &routes {
+            assert!(!route.route.iter().any(|&s| avoid.contains(s)));
+        }
+
+        // Jita's only neighbors cut it off from everything.
+        let mut avoid = Avoid::new();
+        for gate in &map.by_system_id(jita).gates {
+            avoid.insert(gate.destination);
+        }
+        let e = k_shortest_routes(map, jita, amarr, 3, &JumpCost, &avoid).unwrap_err();
+        assert!(matches!(e, Error::Unreachable { avoided: true, .. }));
+
+        // Spur searches cannot carry the entry a route arrives
+        // by.
+        let model = TravelModel::for_class(ShipClass::Cruiser);
+        let e = k_shortest_routes(map, jita, amarr, 3, &model, &Avoid::new()).unwrap_err();
+        assert!(matches!(e, Error::BadCost(_)));
+    }
+}
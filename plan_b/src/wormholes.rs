@@ -0,0 +1,204 @@
+// Copyright © 2018 Po Huit
+// [This program is licensed under the "MIT License"]
+// Please see the file LICENSE in the source
+// distribution of this software for license terms.
+
+//! Wormhole connection overlays for Plan B.
+//!
+//! Wormhole connections come and go, so they are not in the
+//! map data. An overlay file, such as an export from a
+//! mapping tool, lists them as JSON:
+//!
+//! ```text
+//! [
+//!     {"signature": "ABC-123", "type": "K162", "from": "Jita", "to": "J100033",
+//!      "size": "large", "expires": 1700000000}
+//! ]
+//! ```
+//!
+//! The size is the largest class of ship the wormhole
+//! passes, and the expiry a Unix time in seconds. Each
+//! connection works both ways.
+//!
+//! Both are kept on the gates added to the map, but searches
+//! do not check them: prune the map with
+//! `Map::remove_unusable()` before routing. A long-running
+//! process routing ships of several sizes can prune a clone
+//! of the map for each query.
+
+use std::fs;
+use std::io::{BufReader, Read};
+use std::path::Path;
+use std::str::FromStr;
+
+use serde::Deserialize;
+
+use crate::error::*;
+use crate::map::*;
+
+/// Size classes of ship a wormhole may pass, smallest first.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord, Hash, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum WormholeSize {
+    /// Frigates and destroyers.
+    Small,
+    /// Cruisers and battlecruisers.
+    Medium,
+    /// Battleships.
+    Large,
+    /// Freighters and capitals.
+    VeryLarge,
+}
+
+impl FromStr for WormholeSize {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "small" => Ok(WormholeSize::Small),
+            "medium" => Ok(WormholeSize::Medium),
+            "large" => Ok(WormholeSize::Large),
+            "very-large" => Ok(WormholeSize::VeryLarge),
+            _ => Err(format!("unknown wormhole size {}", s)),
+        }
+    }
+}
+
+/// A wormhole connection between two systems, by name.
+#[derive(Clone, Debug, PartialEq, Eq, Deserialize)]
+pub struct Wormhole {
+    /// Cosmic signature of the wormhole.
+    pub signature: String,
+    /// Wormhole type, such as `K162`.
+    #[serde(rename = "type")]
+    pub wormhole_type: String,
+    /// Name of one system.
+    pub from: String,
+    /// Name of the other system.
+    pub to: String,
+    /// Largest class of ship the wormhole passes.
+    pub size: WormholeSize,
+    /// When the wormhole collapses, in seconds since the Unix
+    /// epoch.
+    pub expires: u64,
+}
+
+/// Parse a wormhole connection overlay from the given reader.
+pub fn read_wormholes<R: Read>(reader: R) -> Result<Vec<Wormhole>, Error> {
+    Ok(serde_json::from_reader(reader)?)
+}
+
+/// Read the wormhole connection overlay file at the given
+/// path.
+pub fn load_wormholes<P: AsRef<Path>>(path: P) -> Result<Vec<Wormhole>, Error> {
+    let file = fs::File::open(path).map_err(Error::Io)?;
+    read_wormholes(BufReader::new(file))
+}
+
+impl Map {
+    /// Add the given wormhole connections to the map, both
+    /// ways, as gates of kind `GateKind::Wormhole` at the star
+    /// of each system, keeping each connection's expiry and
+    /// size. Connections that have expired by `now` (a Unix
+    /// time in seconds) are left out, as are those already in
+    /// the map. Returns the number of connections added; if a
+    /// system name is unknown, nothing is added.
+    ///
+    /// Searches check neither expiry nor size: call
+    /// `remove_unusable()` before routing.
+    pub fn add_wormholes(&mut self, wormholes: &[Wormhole], now: u64) -> Result<usize, Error> {
+        let mut links = Vec::new();
+        for wormhole in wormholes {
+            if wormhole.expires > now {
+                links.push(Link {
+                    from: self.try_by_name(&wormhole.from)?.system_index,
+                    to: self.try_by_name(&wormhole.to)?.system_index,
+                    expires: Some(wormhole.expires),
+                    size: Some(wormhole.size),
+                });
+            }
+        }
+        Ok(self.add_links(&links, GateKind::Wormhole))
+    }
+
+    /// Remove the gates that have expired by `now`, a Unix
+    /// time in seconds, or are too small for a ship of size
+    /// `ship`, such as collapsed wormholes. Returns the number
+    /// of connections removed.
+    pub fn remove_unusable(&mut self, now: u64, ship: WormholeSize) -> usize {
+        let removed = self.retain_gates(|g| {
+            g.expires.is_none_or(|t| t > now) && g.size.is_none_or(|size| size >= ship)
+        });
+        // Each connection is a gate each way.
+        removed / 2
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::bridges::*;
+    use crate::search::*;
+    use crate::testing::*;
+
+    const WORMHOLES: &str = r#"[
+        {"signature": "ABC-123", "type": "K162", "from": "Alpha", "to": "Gamma",
+         "size": "medium", "expires": 2000},
+        {"signature": "DEF-456", "type": "N110", "from": "Alpha", "to": "Gamma",
+         "size": "very-large", "expires": 1000}
+    ]"#;
+
+    #[test]
+    fn tiny_wormholes() {
+        let wormholes = read_wormholes(WORMHOLES.as_bytes()).unwrap();
+        assert_eq!("K162", wormholes[0].wormhole_type);
+        assert_eq!(WormholeSize::VeryLarge, wormholes[1].size);
+        let (alpha, gamma) = ("Alpha", "Gamma");
+
+        // At time 2000 both wormholes have collapsed; at 1500
+        // only the second has.
+        let mut map = tiny_map();
+        assert_eq!(0, map.add_wormholes(&wormholes, 2000).unwrap());
+        assert_eq!(1, map.add_wormholes(&wormholes, 1500).unwrap());
+        assert_eq!(0, map.add_wormholes(&wormholes, 1500).unwrap());
+        let (alpha, gamma) = (find_system(&map, alpha), find_system(&map, gamma));
+        let gate = map.by_system_id(alpha).gate_to(gamma).unwrap();
+        assert_eq!(
+            (Some(2000), Some(WormholeSize::Medium)),
+            (gate.expires, gate.size)
+        );
+
+        // The first is too small for a battleship, but a
+        // cruiser may take it.
+        let mut battleship = map.clone();
+        assert_eq!(1, battleship.remove_unusable(1500, WormholeSize::Large));
+        assert_eq!(0, map.remove_unusable(1500, WormholeSize::Medium));
+        let route = shortest_route(&map, gamma, alpha, &Avoid::new()).unwrap();
+        assert_eq!(vec![GateKind::Wormhole], gate_kinds(&map, &route).unwrap());
+
+        // Once it collapses, the stargates are back to being
+        // the only way.
+        assert_eq!(0, map.remove_unusable(1999, WormholeSize::Small));
+        assert_eq!(1, map.remove_unusable(2000, WormholeSize::Small));
+        let route = shortest_route(&map, gamma, alpha, &Avoid::new()).unwrap();
+        assert_eq!(
+            vec![GateKind::Stargate; 2],
+            gate_kinds(&map, &route).unwrap()
+        );
+        assert_eq!(1, map.by_system_id(alpha).gates.len());
+    }
+
+    #[test]
+    fn wormhole_space() {
+        // Wormhole space is in the map, but unreachable
+        // without wormholes.
+        let map = shared_map();
+        let thera = map.by_name("Thera").unwrap();
+        assert!(!thera.known_space());
+        assert!(thera.gates.is_empty());
+        let jita = find_system(map, "Jita");
+        let e = shortest_route(map, jita, thera.system_id, &Avoid::new()).unwrap_err();
+        assert!(matches!(e, Error::Unreachable { .. }));
+        assert!(map.by_name("Jita").unwrap().known_space());
+    }
+}
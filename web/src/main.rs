@@ -6,7 +6,9 @@
 // Plan B: EVE route planner with options
 // Web client
 
-use std::sync::Arc;
+use std::fmt::Display;
+use std::path::PathBuf;
+use std::sync::{Arc, OnceLock};
 
 use axum::{
     extract::{Form, State},
@@ -18,8 +20,28 @@ use serde::Deserialize;
 
 use plan_b::*;
 
+// Most shortest routes shown for one request.
+const ROUTES_LIMIT: usize = 100;
+
+// Server state: the map, and its APSP table for finding all
+// shortest routes. The table is loaded from `apsp_file`, or
+// computed, when first needed.
+struct Planner {
+    map: Map,
+    apsp_file: PathBuf,
+    apsp: OnceLock<APSPTable>,
+}
+
+impl Planner {
+    // The APSP table for the map.
+    fn apsp(&self) -> &APSPTable {
+        self.apsp
+            .get_or_init(|| cached_apsp(&self.map, &self.apsp_file, 0))
+    }
+}
+
 // Display the Plan B front page.
-async fn front_page(_: State<Arc<Map>>) -> Result<Html<String>, (StatusCode, String)> {
+async fn front_page(_: State<Arc<Planner>>) -> Result<Html<String>, (StatusCode, String)> {
     let html = std::fs::read_to_string("static/plan-b.html").map_err(|e| {
         (
             StatusCode::INTERNAL_SERVER_ERROR,
@@ -30,7 +52,7 @@ async fn front_page(_: State<Arc<Map>>) -> Result<Html<String>, (StatusCode, Str
 }
 
 // Display the Plan B favicon.
-async fn favicon(_: State<Arc<Map>>) -> Result<Response, (StatusCode, String)> {
+async fn favicon(_: State<Arc<Planner>>) -> Result<Response, (StatusCode, String)> {
     let favicon = std::fs::read("static/plan-b-favicon.ico").map_err(|e| {
         (
             StatusCode::INTERNAL_SERVER_ERROR,
@@ -47,51 +69,190 @@ async fn favicon(_: State<Arc<Map>>) -> Result<Response, (StatusCode, String)> {
 struct RouteSpec {
     from: String,
     to: String,
+    via: Option<String>,
+    prefer: Option<Preference>,
+    penalty: Option<usize>,
+    avoid: Option<String>,
+    all: Option<String>,
+}
+
+// Translate a Plan B error into an HTTP error response,
+// naming systems where possible. Errors in a form field are
+// prefixed with the field name.
+fn error_response(map: &Map, field: Option<&str>, e: Error) -> (StatusCode, String) {
+    let status = match e {
+        Error::UnknownSystemName(_) | Error::UnknownSystemId(_) => StatusCode::BAD_REQUEST,
+        Error::Unreachable { .. } => StatusCode::UNPROCESSABLE_ENTITY,
+        _ => StatusCode::INTERNAL_SERVER_ERROR,
+    };
+    let message = match e {
+        Error::Unreachable {
+            start,
+            goal,
+            avoided,
+        } => {
+            let avoiding = if avoided {
+                " avoiding the given systems"
+            } else {
+                ""
+            };
+            format!(
+                "no route found from {} to {}{}",
+                &map.by_system_id(start).name,
+                &map.by_system_id(goal).name,
+                avoiding,
+            )
+        }
+        e => e.to_string(),
+    };
+    match field {
+        Some(field) => (status, format!("{}: {}", field, message)),
+        None => (status, message),
+    }
+}
+
+// Look up a comma-separated list of system names from the
+// given form field.
+fn find_systems(
+    map: &Map,
+    field: &str,
+    names: Option<&str>,
+) -> Result<Vec<SystemId>, (StatusCode, String)> {
+    names
+        .unwrap_or("")
+        .split(',')
+        .map(str::trim)
+        .filter(|n| !n.is_empty())
+        .map(|name| {
+            map.try_by_name(name)
+                .map(|s| s.system_id)
+                .map_err(|e| error_response(map, Some(field), e))
+        })
+        .collect()
 }
 
 // Process an EVE route request.
 // https://github.com/joelparkerhenderson/demo-rust-axum/
 // examples/html-form-get-and-post
 async fn search_route(
-    State(map): State<Arc<Map>>,
+    State(planner): State<Arc<Planner>>,
     form: Form<RouteSpec>,
 ) -> Result<String, (StatusCode, String)> {
-    let from = map.by_name(&form.0.from).ok_or_else(|| {
-        (
-            StatusCode::BAD_REQUEST,
-            format!("from: system {} not found", &form.0.from),
-        )
-    })?;
-    let to = map.by_name(&form.0.to).ok_or_else(|| {
-        (
-            StatusCode::BAD_REQUEST,
-            format!("to: system {} not found", &form.0.to),
-        )
-    })?;
-    let route: Vec<&str> = shortest_route(&map, from.system_id, to.system_id)
-        .ok_or_else(|| {
-            (
+    let map = &planner.map;
+    let from = map
+        .try_by_name(&form.0.from)
+        .map_err(|e| error_response(map, Some("from"), e))?;
+    let to = map
+        .try_by_name(&form.0.to)
+        .map_err(|e| error_response(map, Some("to"), e))?;
+    let mut request = RouteRequest::new(from.system_id, to.system_id);
+    request.waypoints = find_systems(map, "via", form.0.via.as_deref())?;
+    request.preference = form.0.prefer.unwrap_or(Preference::Shortest);
+    request.penalty = form.0.penalty.unwrap_or(DEFAULT_PENALTY);
+    for system_id in find_systems(map, "avoid", form.0.avoid.as_deref())? {
+        request.avoid.insert(system_id);
+    }
+
+    // Finding all routes may need the APSP table computed,
+    // so is done off the server thread.
+    if form.0.all.is_some() {
+        if !request.waypoints.is_empty() {
+            return Err((
                 StatusCode::BAD_REQUEST,
-                format!("no route found from {} to {}", &from.name, &to.name,),
-            )
-        })?
+                "all: cannot route via waypoints".to_string(),
+            ));
+        }
+        let planner = planner.clone();
+        return tokio::task::spawn_blocking(move || all_routes(&planner, &request))
+            .await
+            .map_err(|e| (StatusCode::INTERNAL_SERVER_ERROR, e.to_string()))?;
+    }
+
+    let route = request
+        .route(map)
+        .map_err(|e| error_response(map, None, e))?;
+    Ok(names(map, &route.route, route.waypoints()))
+}
+
+// The number of shortest routes for the request, then the
+// first `ROUTES_LIMIT` of them, separated by blank lines.
+fn all_routes(planner: &Planner, request: &RouteRequest) -> Result<String, (StatusCode, String)> {
+    let (map, apsp) = (&planner.map, planner.apsp());
+    let (start, goal, avoid) = (request.start, request.goal, &request.avoid);
+    let error = |e| error_response(map, None, e);
+    let count = count_shortest_routes(map, apsp, start, goal, avoid).map_err(error)?;
+    let routes: Vec<String> = iter_shortest_routes(map, apsp, start, goal, avoid)
+        .map_err(error)?
+        .take(ROUTES_LIMIT)
+        .map(|route| names(map, &route, &[]))
+        .collect();
+    Ok(format!("routes {}\n\n{}", count, routes.join("\n\n")))
+}
+
+// A route as text, one system per line, with the systems at
+// the given route indices marked as waypoints.
+fn names(map: &Map, route: &[SystemId], waypoints: &[usize]) -> String {
+    let route: Vec<String> = route
         .iter()
-        .map(|&system_id| map.by_system_id(system_id).name.as_ref())
+        .enumerate()
+        .map(|(i, &system_id)| {
+            let name = &map.by_system_id(system_id).name;
+            if waypoints.contains(&i) {
+                format!("{} waypoint", name)
+            } else {
+                name.clone()
+            }
+        })
         .collect();
-    Ok(route.join("\n"))
+    route.join("\n")
+}
+
+// Map file given by a `--map <path>` or `--map=<path>`
+// argument, if any, or an error if the path is missing.
+fn map_arg() -> Result<Option<PathBuf>, String> {
+    let mut args = std::env::args_os().skip(1);
+    while let Some(arg) = args.next() {
+        let path = if arg == "--map" {
+            args.next()
+        } else if let Some(path) = arg.to_str().and_then(|a| a.strip_prefix("--map=")) {
+            Some(path.into())
+        } else {
+            continue;
+        };
+        return match path {
+            Some(path) if !path.is_empty() => Ok(Some(PathBuf::from(path))),
+            _ => Err("--map: missing map file path".to_string()),
+        };
+    }
+    Ok(None)
+}
+
+// Report an error starting up, and exit.
+fn fail<E: Display>(e: E) -> ! {
+    eprintln!("plan-b-web: {}", e);
+    std::process::exit(1)
 }
 
 // Plan B web service.
 #[tokio::main(flavor = "current_thread")]
 async fn main() {
-    let map = Map::fetch().expect("internal error: could not find map data: {e}");
-    let map = Arc::new(map);
+    // Load the map. The APSP table waits until it is needed.
+    let map_file = map_arg()
+        .unwrap_or_else(|e| fail(e))
+        .map_or_else(map_path, Ok)
+        .unwrap_or_else(|e| fail(e));
+    let map = Map::from_path(&map_file).unwrap_or_else(|e| fail(e));
+    let planner = Arc::new(Planner {
+        map,
+        apsp_file: apsp_path(&map_file),
+        apsp: OnceLock::new(),
+    });
 
     let app = Router::new()
         .route("/", get(front_page))
         .route("/favicon.ico", get(favicon))
         .route("/", post(search_route))
-        .with_state(map);
+        .with_state(planner);
     axum::Server::bind(&"0.0.0.0:9146".parse().unwrap())
         .serve(app.into_make_service())
         .await